@@ -4,6 +4,12 @@ use bindgen::Builder;
 use std::{env::var, path::PathBuf};
 
 fn main() {
+    // Loop devices only exist on Linux, other platforms get a stub API without any bindings.
+    let target_os = var("CARGO_CFG_TARGET_OS").unwrap();
+    if target_os != "linux" && target_os != "android" {
+        return;
+    }
+
     let bindings = Builder::default()
         .header_contents("wrapper.h", "#include <linux/loop.h>")
         .derive_default(true)
@@ -1,16 +1,30 @@
 #[macro_use]
 extern crate clap;
 extern crate loopdev;
+#[macro_use]
+extern crate serde_json;
 
-use loopdev::{LoopControl, LoopDevice};
+use loopdev::{DeviceNumber, LoopControl, LoopDevice, LoopDeviceInfo};
+use std::convert::TryFrom;
 use std::io::{self, Write};
+use std::path::Path;
 use std::process::exit;
+use std::time::Duration;
+
+fn print_json(devices: &[LoopDeviceInfo]) {
+    println!("{:#}", json!({ "loopdevices": devices }));
+}
 
-fn find() -> io::Result<()> {
-    println!(
-        "{}",
-        LoopControl::open()?.next_free()?.path().unwrap().display()
-    );
+fn find(matches: Option<&clap::ArgMatches>) -> io::Result<()> {
+    let json = matches.is_some_and(|matches| matches.is_present("json"));
+    let lc = LoopControl::open()?;
+    let (number, _) = lc.next_free_with_number()?;
+    let path = DeviceNumber(number).path();
+    if json {
+        println!("{}", json!({ "device": path, "number": number }));
+    } else {
+        println!("{}", path.display());
+    }
     Ok(())
 }
 
@@ -22,41 +36,133 @@ fn attach(matches: &clap::ArgMatches) -> io::Result<()> {
     let read_only = matches.is_present("read_only");
     let auto_clear = matches.is_present("auto_clear");
     let part_scan = matches.is_present("part_scan");
-    let loopdev = match matches.value_of("loopdev") {
-        Some(loopdev) => LoopDevice::open(&loopdev)?,
-        None => LoopControl::open().and_then(|lc| lc.next_free())?,
+    let block_size = value_t!(matches.value_of("blocksize"), u32).ok();
+    let json = matches.is_present("json");
+    let device = value_t!(matches.value_of("device"), DeviceNumber).ok();
+    let loopdev = match (matches.value_of("loopdev"), device) {
+        (Some(loopdev), _) => LoopDevice::try_from(Path::new(loopdev))?,
+        (None, Some(number)) => LoopControl::open()?.open_device(number)?,
+        (None, None) => LoopControl::open().and_then(|lc| lc.next_free())?,
     };
-    loopdev
+    let mut options = loopdev
         .with()
         .offset(offset)
         .size_limit(size_limit)
         .read_only(read_only)
         .autoclear(auto_clear)
-        .part_scan(part_scan)
-        .attach(image)?;
+        .part_scan(part_scan);
+    if let Some(block_size) = block_size {
+        options = options.block_size(block_size);
+    }
+    options.attach(image)?;
 
-    if !quiet {
+    if json {
+        print_json(&[LoopDeviceInfo {
+            path: loopdev.path().unwrap(),
+            info: loopdev.status()?,
+            ..Default::default()
+        }]);
+    } else if !quiet {
         println!("{}", loopdev.path().unwrap().display());
     }
     Ok(())
 }
 
 fn detach(matches: &clap::ArgMatches) -> io::Result<()> {
-    let loopdev = matches.value_of("file").unwrap();
-    LoopDevice::open(loopdev)?.detach()
+    let loopdev = LoopDevice::try_from(Path::new(matches.value_of("file").unwrap()))?;
+    if matches.is_present("no_wait") {
+        loopdev.detach()
+    } else {
+        loopdev.detach_and_wait(Duration::from_secs(2))
+    }
 }
 
 fn set_capacity(matches: &clap::ArgMatches) -> io::Result<()> {
     let loopdev = matches.value_of("file").unwrap();
-    LoopDevice::open(loopdev)?.set_capacity()
+    LoopDevice::try_from(Path::new(loopdev))?.set_capacity()
+}
+
+fn set_block_size(matches: &clap::ArgMatches) -> io::Result<()> {
+    let loopdev = matches.value_of("file").unwrap();
+    let block_size = value_t!(matches.value_of("size"), u32).unwrap();
+    LoopDevice::try_from(Path::new(loopdev))?.set_block_size(block_size)
+}
+
+fn validate_block_size(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(size) if size.is_power_of_two() && (512..=4096).contains(&size) => Ok(()),
+        _ => Err(format!(
+            "{} is not a power of two between 512 and 4096",
+            value
+        )),
+    }
+}
+
+fn validate_number(value: String) -> Result<(), String> {
+    value
+        .parse::<DeviceNumber>()
+        .map(drop)
+        .map_err(|err| err.to_string())
 }
 
 fn list(matches: Option<&clap::ArgMatches>) -> io::Result<()> {
-    let (_free, _used) = match matches {
-        Some(matches) => (matches.is_present("free"), matches.is_present("used")),
-        None => (false, false),
+    let (free, used, json, associated) = match matches {
+        Some(matches) => (
+            matches.is_present("free"),
+            matches.is_present("used"),
+            matches.is_present("json"),
+            matches.value_of("associated"),
+        ),
+        None => (false, false, false, None),
     };
-    unimplemented!();
+    if let Some(backing_file) = associated {
+        let devices = loopdev::find_all_by_backing_file(backing_file)?
+            .into_iter()
+            .map(|loopdev| {
+                Ok(LoopDeviceInfo {
+                    path: loopdev.path().unwrap(),
+                    info: loopdev.status()?,
+                    ..Default::default()
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        return print_devices(&devices, json);
+    }
+    // Only list the used devices unless asked otherwise, like `losetup --list`.
+    let used = used || !free;
+
+    let mut devices = Vec::new();
+    for device in LoopControl::open()?.probe()? {
+        if let Some(ref err) = device.error {
+            return Err(io::Error::other(format!(
+                "{}: {}",
+                device.path.display(),
+                err
+            )));
+        }
+        // Only attached devices have a size.
+        if (device.size.is_some() && used) || (device.size.is_none() && free) {
+            devices.push(device);
+        }
+    }
+
+    print_devices(&devices, json)
+}
+
+fn print_devices(devices: &[LoopDeviceInfo], json: bool) -> io::Result<()> {
+    if json {
+        print_json(devices);
+    } else {
+        for device in devices {
+            match device.info.backing_file {
+                Some(ref backing_file) => {
+                    println!("{}: ({})", device.path.display(), backing_file.display())
+                }
+                None => println!("{}", device.path.display()),
+            }
+        }
+    }
+    Ok(())
 }
 
 fn main() {
@@ -66,39 +172,52 @@ fn main() {
         (about: crate_description!())
         (@subcommand find =>
             (about: "find the next free loop device")
+            (@arg json: -j --json "print the device and its number as json")
         )
         (@subcommand attach =>
             (about: "attach the loop device to a backing file")
             (@arg image: +required "the backing file to attach")
-            (@arg loopdev: "the loop device to attach")
+            (@arg loopdev: conflicts_with[device] "the loop device to attach")
+            (@arg device: -d --device +takes_value {validate_number} "attach the loop device with this number or path, creating it if needed")
             (@arg offset: -o --offset +takes_value "the offset within the file to start at")
             (@arg sizelimit: -s --sizelimit +takes_value "the file is limited to this size")
             (@arg read_only: -r --readonly "set up a read-only loop device")
             (@arg auto_clear: -a --autoclear "set the autoclear flag")
             (@arg part_scan: -p --partscan "set the part-scan flag")
+            (@arg blocksize: -b --blocksize +takes_value {validate_block_size} "set the logical block size")
             (@arg quiet: -q --quiet "don't print the device name")
+            (@arg json: -j --json "print the device as json")
         )
         (@subcommand detach =>
             (about: "detach the loop device from the backing file")
             (@arg file: +required "The file to detach")
+            (@arg no_wait: --("no-wait") "don't wait for the device to be detached")
         )
         (@subcommand setcapacity =>
             (about: "inform the loop driver of a change in size of the backing file")
             (@arg file: +required "The file to set the capacity of")
         )
+        (@subcommand blocksize =>
+            (about: "set the logical block size of the loop device")
+            (@arg file: +required "The file to set the block size of")
+            (@arg size: +required {validate_block_size} "The block size in bytes")
+        )
         (@subcommand list =>
             (about: "list the available loop devices")
             (@arg free: -f --free "find free devices")
             (@arg used: -u --used "find used devices")
+            (@arg json: -j --json "print the devices as json")
+            (@arg associated: --associated +takes_value "list the devices associated with a file")
         )
     )
     .get_matches();
 
     let result = match matches.subcommand() {
-        ("find", _) => find(),
+        ("find", matches) => find(matches),
         ("attach", Some(matches)) => attach(matches),
         ("detach", Some(matches)) => detach(matches),
         ("setcapacity", Some(matches)) => set_capacity(matches),
+        ("blocksize", Some(matches)) => set_block_size(matches),
         (_, matches) => list(matches),
     };
 
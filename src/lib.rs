@@ -6,6 +6,10 @@
 //!
 //! Provides rust interface with similar functionality to the Linux utility `losetup`.
 //!
+//! Loop devices only exist on Linux and Android. On other platforms the same API is available so
+//! dependent crates still build, but opening any device fails with
+//! [`Unsupported`](std::io::ErrorKind::Unsupported).
+//!
 //! # Examples
 //!
 //! Default options:
@@ -37,482 +41,377 @@
 //! // ...
 //! ld.detach().unwrap();
 //! ```
-use crate::bindings::{
-    loop_info64, LOOP_CLR_FD, LOOP_CTL_ADD, LOOP_CTL_GET_FREE, LOOP_SET_CAPACITY, LOOP_SET_FD,
-    LOOP_SET_STATUS64, LO_FLAGS_AUTOCLEAR, LO_FLAGS_PARTSCAN, LO_FLAGS_READ_ONLY,
-};
-#[cfg(feature = "direct_io")]
-use bindings::LOOP_SET_DIRECT_IO;
-use libc::{c_int, ioctl};
-use std::{
-    default::Default,
-    fs::{File, OpenOptions},
-    io,
-    os::unix::prelude::*,
-    path::{Path, PathBuf},
-};
-
-#[allow(non_camel_case_types)]
-#[allow(dead_code)]
-#[allow(non_snake_case)]
-mod bindings {
-    include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
-}
-
-#[cfg(all(not(target_os = "android"), not(target_env = "musl")))]
-type IoctlRequest = libc::c_ulong;
-#[cfg(any(target_os = "android", target_env = "musl"))]
-type IoctlRequest = libc::c_int;
-
-const LOOP_CONTROL: &str = "/dev/loop-control";
-#[cfg(not(target_os = "android"))]
-const LOOP_PREFIX: &str = "/dev/loop";
-#[cfg(target_os = "android")]
-const LOOP_PREFIX: &str = "/dev/block/loop";
-
-/// Interface to the loop control device: `/dev/loop-control`.
-#[derive(Debug)]
-pub struct LoopControl {
-    dev_file: File,
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod unsupported;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use crate::linux::*;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub use crate::unsupported::*;
+use std::{fmt, path::PathBuf};
+
+/// The status of a loop device as reported by the kernel. Created with [`LoopDevice::status`()].
+///
+/// With the `serde` feature enabled this can be serialized, the field names match the output of
+/// `losetup --json`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub struct LoopInfo {
+    /// Number of the loop device, ie `0` for `/dev/loop0`.
+    pub number: u32,
+    /// Path of the backing file.
+    #[cfg_attr(feature = "serde", serde(rename = "back-file"))]
+    pub backing_file: Option<PathBuf>,
+    /// Device number of the filesystem the backing file is on (the `st_dev` of the file).
+    pub backing_device: u64,
+    /// Inode number of the backing file (the `st_ino` of the file).
+    #[cfg_attr(feature = "serde", serde(rename = "back-ino"))]
+    pub backing_inode: u64,
+    /// Device number of the backing file if it is a device itself (the `st_rdev` of the file).
+    pub backing_rdevice: u64,
+    /// Offset in bytes from the start of the backing file the data starts at.
+    pub offset: u64,
+    /// Maximum size of the data in bytes, `0` means the whole backing file is used.
+    #[cfg_attr(feature = "serde", serde(rename = "sizelimit"))]
+    pub size_limit: u64,
+    /// Read only flag
+    #[cfg_attr(feature = "serde", serde(rename = "ro"))]
+    pub read_only: bool,
+    /// Autoclear flag
+    pub autoclear: bool,
+    /// Partition scan flag
+    #[cfg_attr(feature = "serde", serde(rename = "partscan"))]
+    pub part_scan: bool,
+    /// Direct I/O flag
+    #[cfg_attr(feature = "serde", serde(rename = "dio"))]
+    pub direct_io: bool,
+    /// Type of the legacy in-kernel encryption, `0` means no encryption.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub encrypt_type: u32,
+    /// Name of the cipher used by the legacy in-kernel encryption.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub crypt_name: Option<String>,
+    /// Driver specific initialization data of the legacy transfer functions (`lo_init`).
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub init: [u64; 2],
 }
 
-impl LoopControl {
-    /// Opens the loop control device.
-    ///
-    /// # Errors
-    ///
-    /// This function will return an error for various reasons when opening
-    /// the loop control file `/dev/loop-control`. See
-    /// [`OpenOptions::open`](https://doc.rust-lang.org/std/fs/struct.OpenOptions.html)
-    /// for further details.
-    pub fn open() -> io::Result<Self> {
-        Ok(Self {
-            dev_file: OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(LOOP_CONTROL)?,
-        })
+bitflags::bitflags! {
+    /// The flags of a loop device, the `lo_flags` the kernel reports. Get them from
+    /// [`LoopInfo::flags`] to print, store or compare them in one go.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+    pub struct LoopFlags: u32 {
+        /// The device is read only.
+        const READ_ONLY = 1;
+        /// The device is detached when it is closed for the last time.
+        const AUTOCLEAR = 1 << 2;
+        /// The partition table of the device is scanned.
+        const PARTSCAN = 1 << 3;
+        /// The backing file is accessed with direct I/O.
+        const DIRECT_IO = 1 << 4;
     }
+}
 
-    /// Finds and opens the next available loop device.
-    ///
-    /// # Examples
-    ///
-    /// ```no_run
-    /// use loopdev::LoopControl;
-    /// let lc = LoopControl::open().unwrap();
-    /// let ld = lc.next_free().unwrap();
-    /// println!("{}", ld.path().unwrap().display());
-    /// ```
-    ///
-    /// # Errors
-    ///
-    /// This function will return an error for various reasons when opening
-    /// the loop device file `/dev/loopX`. See
-    /// [`OpenOptions::open`](https://doc.rust-lang.org/std/fs/struct.OpenOptions.html)
-    /// for further details.
-    pub fn next_free(&self) -> io::Result<LoopDevice> {
-        let dev_num = ioctl_to_error(unsafe {
-            ioctl(
-                self.dev_file.as_raw_fd() as c_int,
-                LOOP_CTL_GET_FREE as IoctlRequest,
-            )
-        })?;
-        LoopDevice::open(format!("{}{}", LOOP_PREFIX, dev_num))
+impl LoopInfo {
+    /// All flags of the device as a single value.
+    pub fn flags(&self) -> LoopFlags {
+        let mut flags = LoopFlags::empty();
+        flags.set(LoopFlags::READ_ONLY, self.read_only);
+        flags.set(LoopFlags::AUTOCLEAR, self.autoclear);
+        flags.set(LoopFlags::PARTSCAN, self.part_scan);
+        flags.set(LoopFlags::DIRECT_IO, self.direct_io);
+        flags
     }
 
-    /// Add and opens a new loop device.
-    ///
-    /// # Examples
-    ///
-    /// ```no_run
-    /// use loopdev::LoopControl;
-    /// let lc = LoopControl::open().unwrap();
-    /// let ld = lc.add(1).unwrap();
-    /// println!("{}", ld.path().unwrap().display());
-    /// ```
-    ///
-    /// # Errors
-    ///
-    /// This funcitons will return an error when a loop device with the passed
-    /// number exists or opening the newly created device fails.
-    pub fn add(&self, n: u32) -> io::Result<LoopDevice> {
-        let dev_num = ioctl_to_error(unsafe {
-            ioctl(
-                self.dev_file.as_raw_fd() as c_int,
-                LOOP_CTL_ADD as IoctlRequest,
-                n as c_int,
-            )
-        })?;
-        LoopDevice::open(format!("{}{}", LOOP_PREFIX, dev_num))
+    /// Set all flags of the device at once, replacing the individual flag fields.
+    pub fn set_flags(&mut self, flags: LoopFlags) {
+        self.read_only = flags.contains(LoopFlags::READ_ONLY);
+        self.autoclear = flags.contains(LoopFlags::AUTOCLEAR);
+        self.part_scan = flags.contains(LoopFlags::PARTSCAN);
+        self.direct_io = flags.contains(LoopFlags::DIRECT_IO);
     }
 }
 
-impl AsRawFd for LoopControl {
-    fn as_raw_fd(&self) -> RawFd {
-        self.dev_file.as_raw_fd()
-    }
+/// A loop device together with its status.
+///
+/// With the `serde` feature enabled this serializes to the same shape as an entry of
+/// `losetup --json --list`, the fields filled in by [`LoopControl::probe`] are only included
+/// when they are set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LoopDeviceInfo {
+    /// Path of the loop device, ie `/dev/loop0`.
+    #[cfg_attr(feature = "serde", serde(rename = "name"))]
+    pub path: PathBuf,
+    /// Status of the loop device.
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub info: LoopInfo,
+    /// Size of the device in bytes, `None` if the device is not attached or was not probed.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub size: Option<u64>,
+    /// Names of the devices holding the loop device, ie `dm-0`.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    pub holders: Vec<String>,
+    /// The error that stopped the device from being probed completely.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub error: Option<String>,
 }
 
-impl IntoRawFd for LoopControl {
-    fn into_raw_fd(self) -> RawFd {
-        self.dev_file.into_raw_fd()
-    }
+/// The status of a loop device together with its current size, read at once. Created with
+/// [`LoopDevice::snapshot`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub struct LoopSnapshot {
+    /// Status of the loop device.
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub info: LoopInfo,
+    /// Size of the device in bytes.
+    pub size: u64,
+    /// Logical block size of the device in bytes.
+    #[cfg_attr(feature = "serde", serde(rename = "log-sec"))]
+    pub block_size: u32,
 }
 
-/// Interface to a loop device ie `/dev/loop0`.
-#[derive(Debug)]
-pub struct LoopDevice {
-    device: File,
+/// The size and block sizes of a loop device, as needed to create a filesystem on it. Created
+/// with [`LoopDevice::geometry`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Geometry {
+    /// Size of the device in bytes.
+    pub size_bytes: u64,
+    /// Logical block size of the device in bytes, the smallest unit it can address.
+    pub logical_block_size: u32,
+    /// Physical block size of the device in bytes as reported by the kernel.
+    pub physical_block_size: u32,
 }
 
-impl AsRawFd for LoopDevice {
-    fn as_raw_fd(&self) -> RawFd {
-        self.device.as_raw_fd()
+impl Geometry {
+    /// Number of logical blocks of the device.
+    pub fn sectors(&self) -> u64 {
+        self.size_bytes / u64::from(self.logical_block_size.max(1))
     }
 }
 
-impl IntoRawFd for LoopDevice {
-    fn into_raw_fd(self) -> RawFd {
-        self.device.into_raw_fd()
-    }
+/// Options for attaching a loop device, independent of the device they are applied to.
+///
+/// Unlike [`AttachOptions`], which is tied to the device it was created from, an `AttachConfig`
+/// can be built once, inspected, cloned and used to attach any number of devices with
+/// [`LoopDevice::attach_with_config`].
+///
+/// # Examples
+///
+/// ```no_run
+/// use loopdev::{AttachConfig, LoopControl};
+/// let config = AttachConfig::new().read_only(true).part_scan(true);
+/// let lc = LoopControl::open().unwrap();
+/// for image in ["a.img", "b.img"] {
+///     let ld = lc.next_free().unwrap();
+///     ld.attach_with_config(image, &config).unwrap();
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[must_use]
+pub struct AttachConfig {
+    /// The status to attach with. The fields the kernel reports as read only (ie `number`) and
+    /// `backing_file` are ignored, setting `direct_io` opens the backing file with `O_DIRECT`.
+    pub info: LoopInfo,
+    /// Logical block size of the device in bytes, the kernel default is used when `None`.
+    pub block_size: Option<u32>,
+    /// Whether to take an exclusive advisory lock on the backing file.
+    pub exclusive: bool,
+    /// Whether to pin the size limit to the length of the backing file at attach time.
+    pub size_limit_from_file: bool,
+    /// Whether to detach the current backing file of a device that is already attached.
+    pub replace: bool,
+    /// The size of the sparse backing file to create when attaching by path and the file does
+    /// not exist yet.
+    pub create_backing: Option<u64>,
 }
 
-impl LoopDevice {
-    /// Opens a loop device.
-    ///
-    /// # Errors
-    ///
-    /// This function will return an error for various reasons when opening
-    /// the given loop device file. See
-    /// [`OpenOptions::open`](https://doc.rust-lang.org/std/fs/struct.OpenOptions.html)
-    /// for further details.
-    pub fn open<P: AsRef<Path>>(dev: P) -> io::Result<Self> {
-        // TODO create dev if it does not exist and begins with LOOP_PREFIX
-        Ok(Self {
-            device: OpenOptions::new().read(true).write(true).open(dev)?,
-        })
-    }
-
-    /// Attach the loop device to a file with given options.
-    ///
-    /// # Examples
-    ///
-    /// Attach the device to a file.
-    ///
-    /// ```no_run
-    /// use loopdev::LoopDevice;
-    /// let mut ld = LoopDevice::open("/dev/loop0").unwrap();
-    /// ld.with().part_scan(true).attach("disk.img").unwrap();
-    /// # ld.detach().unwrap();
-    /// ```
-    pub fn with(&self) -> AttachOptions<'_> {
-        AttachOptions {
-            device: self,
-            info: bindings::loop_info64::default(),
-            #[cfg(feature = "direct_io")]
-            direct_io: false,
-        }
+impl AttachConfig {
+    /// Create a config with the default options, attaching the whole backing file.
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    /// Attach the loop device to a file that maps to the whole file.
-    ///
-    /// # Examples
-    ///
-    /// Attach the device to a file.
-    ///
-    /// ```no_run
-    /// use loopdev::LoopDevice;
-    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
-    /// ld.attach_file("disk.img").unwrap();
-    /// # ld.detach().unwrap();
-    /// ```
-    ///
-    /// # Errors
-    ///
-    /// This function will return an error for various reasons. Either when
-    /// opening the backing file (see
-    /// [`OpenOptions::open`](https://doc.rust-lang.org/std/fs/struct.OpenOptions.html)
-    /// for further details) or when calling the ioctl to attach the backing
-    /// file to the device.
-    pub fn attach_file<P: AsRef<Path>>(&self, backing_file: P) -> io::Result<()> {
-        let info = loop_info64 {
-            ..Default::default()
-        };
-
-        Self::attach_with_loop_info(self, backing_file, info)
-    }
-
-    /// Attach the loop device to a file with `loop_info64`.
-    fn attach_with_loop_info(
-        &self, // TODO should be mut? - but changing it is a breaking change
-        backing_file: impl AsRef<Path>,
-        info: loop_info64,
-    ) -> io::Result<()> {
-        let write_access = (info.lo_flags & LO_FLAGS_READ_ONLY) == 0;
-        let bf = OpenOptions::new()
-            .read(true)
-            .write(write_access)
-            .open(backing_file)?;
-        self.attach_fd_with_loop_info(bf, info)
+    /// Replace the status to attach with. Any option set after this is applied on top of `info`.
+    pub fn with_info(mut self, info: LoopInfo) -> Self {
+        self.info = info;
+        self
     }
 
-    /// Attach the loop device to a fd with `loop_info`.
-    fn attach_fd_with_loop_info(&self, bf: impl AsRawFd, info: loop_info64) -> io::Result<()> {
-        // Attach the file
-        ioctl_to_error(unsafe {
-            ioctl(
-                self.device.as_raw_fd() as c_int,
-                LOOP_SET_FD as IoctlRequest,
-                bf.as_raw_fd() as c_int,
-            )
-        })?;
-
-        let result = unsafe {
-            ioctl(
-                self.device.as_raw_fd() as c_int,
-                LOOP_SET_STATUS64 as IoctlRequest,
-                &info,
-            )
-        };
-        match ioctl_to_error(result) {
-            Err(err) => {
-                // Ignore the error to preserve the original error
-                let _detach_err = self.detach();
-                Err(err)
-            }
-            Ok(_) => Ok(()),
-        }
+    /// Offset in bytes from the start of the backing file the data will start at.
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.info.offset = offset;
+        self
     }
 
-    /// Get the path of the loop device.
-    pub fn path(&self) -> Option<PathBuf> {
-        let mut p = PathBuf::from("/proc/self/fd");
-        p.push(self.device.as_raw_fd().to_string());
-        std::fs::read_link(&p).ok()
+    /// Maximum size of the data in bytes.
+    pub fn size_limit(mut self, size_limit: u64) -> Self {
+        self.info.size_limit = size_limit;
+        self
     }
 
-    /// Get the device major number
-    ///
-    /// # Errors
-    ///
-    /// This function needs to stat the backing file and can fail if there is
-    /// an IO error.
-    #[allow(clippy::unnecessary_cast)]
-    pub fn major(&self) -> io::Result<u32> {
-        self.device
-            .metadata()
-            .map(|m| unsafe { libc::major(m.rdev()) })
-            .map(|m| m as u32)
+    /// Offset in sectors (ie the LBA of a partition) from the start of the backing file. The
+    /// sector size is the [`block_size`](AttachConfig::block_size) set so far, or 512 bytes.
+    /// An offset that overflows is rejected when attaching.
+    pub fn offset_sectors(self, lba: u64) -> Self {
+        let offset = lba.saturating_mul(self.sector_size());
+        self.offset(offset)
     }
 
-    /// Get the device major number
-    ///
-    /// # Errors
-    ///
-    /// This function needs to stat the backing file and can fail if there is
-    /// an IO error.
-    #[allow(clippy::unnecessary_cast)]
-    pub fn minor(&self) -> io::Result<u32> {
-        self.device
-            .metadata()
-            .map(|m| unsafe { libc::minor(m.rdev()) })
-            .map(|m| m as u32)
+    /// Maximum size of the data in sectors. The sector size is the
+    /// [`block_size`](AttachConfig::block_size) set so far, or 512 bytes. A size limit that
+    /// overflows is rejected when attaching.
+    pub fn size_limit_sectors(self, count: u64) -> Self {
+        let size_limit = count.saturating_mul(self.sector_size());
+        self.size_limit(size_limit)
     }
 
-    /// Detach a loop device from its backing file.
-    ///
-    /// Note that the device won't fully detach until a short delay after the underling device file
-    /// gets closed. This happens when `LoopDev` goes out of scope so you should ensure the `LoopDev`
-    /// lives for a short a time as possible.
-    ///
-    /// # Examples
-    ///
-    /// ```no_run
-    /// use loopdev::LoopDevice;
-    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
-    /// # ld.attach_file("disk.img").unwrap();
-    /// ld.detach().unwrap();
-    /// ```
-    ///
-    /// # Errors
-    ///
-    /// This function will return an error for various reasons when calling the
-    /// ioctl to detach the backing file from the device.
-    pub fn detach(&self) -> io::Result<()> {
-        ioctl_to_error(unsafe {
-            ioctl(
-                self.device.as_raw_fd() as c_int,
-                LOOP_CLR_FD as IoctlRequest,
-                0,
-            )
-        })?;
-        Ok(())
+    /// Set read only flag. The backing file is then also opened read only.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.info.read_only = read_only;
+        self
     }
 
-    /// Resize a live loop device. If the size of the backing file changes this can be called to
-    /// inform the loop driver about the new size.
-    ///
-    /// # Errors
-    ///
-    /// This function will return an error for various reasons when calling the
-    /// ioctl to set the capacity of the device.
-    pub fn set_capacity(&self) -> io::Result<()> {
-        ioctl_to_error(unsafe {
-            ioctl(
-                self.device.as_raw_fd() as c_int,
-                LOOP_SET_CAPACITY as IoctlRequest,
-                0,
-            )
-        })?;
-        Ok(())
+    /// Set autoclear flag
+    pub fn autoclear(mut self, autoclear: bool) -> Self {
+        self.info.autoclear = autoclear;
+        self
     }
 
     /// Enable or disable direct I/O for the backing file.
-    ///
-    /// # Errors
-    ///
-    /// This function will return an error for various reasons when calling the
-    /// ioctl to set the direct io flag for the device.
     #[cfg(feature = "direct_io")]
-    pub fn set_direct_io(&self, direct_io: bool) -> io::Result<()> {
-        ioctl_to_error(unsafe {
-            ioctl(
-                self.device.as_raw_fd() as c_int,
-                LOOP_SET_DIRECT_IO as IoctlRequest,
-                if direct_io { 1 } else { 0 },
-            )
-        })?;
-        Ok(())
+    pub fn set_direct_io(mut self, direct_io: bool) -> Self {
+        self.info.direct_io = direct_io;
+        self
     }
-}
 
-/// Used to set options when attaching a device. Created with [`LoopDevice::with`()].
-///
-/// # Examples
-///
-/// Enable partition scanning on attach:
-///
-/// ```no_run
-/// use loopdev::LoopDevice;
-/// let mut ld = LoopDevice::open("/dev/loop0").unwrap();
-/// ld.with()
-///     .part_scan(true)
-///     .attach("disk.img")
-///     .unwrap();
-/// # ld.detach().unwrap();
-/// ```
-///
-/// A 1MiB slice of the file located at 1KiB into the file.
-///
-/// ```no_run
-/// use loopdev::LoopDevice;
-/// let mut ld = LoopDevice::open("/dev/loop0").unwrap();
-/// ld.with()
-///     .offset(1024*1024)
-///     .size_limit(1024*1024*1024)
-///     .attach("disk.img")
-///     .unwrap();
-/// # ld.detach().unwrap();
-/// ```
-#[must_use]
-pub struct AttachOptions<'d> {
-    device: &'d LoopDevice,
-    info: loop_info64,
-    #[cfg(feature = "direct_io")]
-    direct_io: bool,
-}
-
-impl AttachOptions<'_> {
-    /// Offset in bytes from the start of the backing file the data will start at.
-    pub fn offset(mut self, offset: u64) -> Self {
-        self.info.lo_offset = offset;
+    /// Logical block size of the device in bytes. This must be a power of two between 512 and the
+    /// page size of the system.
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.block_size = Some(block_size);
         self
     }
 
-    /// Maximum size of the data in bytes.
-    pub fn size_limit(mut self, size_limit: u64) -> Self {
-        self.info.lo_sizelimit = size_limit;
+    /// Force the kernel to scan the partition table on a newly created loop device.
+    pub fn part_scan(mut self, enable: bool) -> Self {
+        self.info.part_scan = enable;
         self
     }
 
-    /// Set read only flag
-    pub fn read_only(mut self, read_only: bool) -> Self {
-        if read_only {
-            self.info.lo_flags |= LO_FLAGS_READ_ONLY;
-        } else {
-            self.info.lo_flags &= !LO_FLAGS_READ_ONLY;
-        }
+    /// Replace all flags at once. Setting [`LoopFlags::DIRECT_IO`] opens the backing file with
+    /// `O_DIRECT` like [`set_direct_io`](AttachConfig::set_direct_io).
+    pub fn flags(mut self, flags: LoopFlags) -> Self {
+        self.info.set_flags(flags);
         self
     }
 
-    /// Set autoclear flag
-    pub fn autoclear(mut self, autoclear: bool) -> Self {
-        if autoclear {
-            self.info.lo_flags |= LO_FLAGS_AUTOCLEAR;
-        } else {
-            self.info.lo_flags &= !LO_FLAGS_AUTOCLEAR;
-        }
+    /// Take an exclusive advisory lock (`flock`) on the backing file for as long as the device
+    /// is attached.
+    pub fn exclusive(mut self, exclusive: bool) -> Self {
+        self.exclusive = exclusive;
         self
     }
 
-    // Enable or disable direct I/O for the backing file.
-    #[cfg(feature = "direct_io")]
-    pub fn set_direct_io(mut self, direct_io: bool) -> Self {
-        self.direct_io = direct_io;
+    /// Set the size limit to the length of the backing file after the offset when attaching,
+    /// overriding [`size_limit`](AttachConfig::size_limit). The device then keeps its size if
+    /// the file grows later.
+    pub fn size_limit_from_file(mut self, enable: bool) -> Self {
+        self.size_limit_from_file = enable;
         self
     }
 
-    /// Force the kernel to scan the partition table on a newly created loop device. Note that the
-    /// partition table parsing depends on sector sizes. The default is sector size is 512 bytes
-    pub fn part_scan(mut self, enable: bool) -> Self {
-        if enable {
-            self.info.lo_flags |= LO_FLAGS_PARTSCAN;
-        } else {
-            self.info.lo_flags &= !LO_FLAGS_PARTSCAN;
-        }
+    /// Replace the backing file of a device that is already attached instead of failing with
+    /// `EBUSY`.
+    pub fn replace(mut self, replace: bool) -> Self {
+        self.replace = replace;
         self
     }
 
-    /// Attach the loop device to a file with the set options.
-    ///
-    /// # Errors
-    ///
-    /// This function will return an error for various reasons. Either when
-    /// opening the backing file (see
-    /// [`OpenOptions::open`](https://doc.rust-lang.org/std/fs/struct.OpenOptions.html)
-    /// for further details) or when calling the ioctl to attach the backing
-    /// file to the device.
-    pub fn attach(self, backing_file: impl AsRef<Path>) -> io::Result<()> {
-        self.device.attach_with_loop_info(backing_file, self.info)?;
-        #[cfg(feature = "direct_io")]
-        if self.direct_io {
-            self.device.set_direct_io(self.direct_io)?;
-        }
-        Ok(())
+    /// Create the backing file with a length of `size` bytes if it does not exist when attaching
+    /// by path. Existing files are attached as they are.
+    pub fn create_backing(mut self, size: u64) -> Self {
+        self.create_backing = Some(size);
+        self
     }
 
-    /// Attach the loop device to an fd
-    ///
-    /// # Errors
-    ///
-    /// This function will return an error for various reasons when calling the
-    /// ioctl to attach the backing file to the device.
-    pub fn attach_fd(self, backing_file_fd: impl AsRawFd) -> io::Result<()> {
-        self.device
-            .attach_fd_with_loop_info(backing_file_fd, self.info)?;
-        #[cfg(feature = "direct_io")]
-        if self.direct_io {
-            self.device.set_direct_io(self.direct_io)?;
-        }
-        Ok(())
+    fn sector_size(&self) -> u64 {
+        u64::from(self.block_size.unwrap_or(512))
     }
 }
 
-fn ioctl_to_error(ret: i32) -> io::Result<i32> {
-    if ret < 0 {
-        Err(io::Error::last_os_error())
-    } else {
-        Ok(ret)
+/// Errors specific to loop devices. These are returned as the inner error of an [`io::Error`]
+/// and can be told apart with [`downcast_ref`](https://doc.rust-lang.org/std/error/trait.Error.html#method.downcast_ref).
+///
+/// # Examples
+///
+/// ```no_run
+/// use loopdev::{Error, LoopDevice};
+/// let ld = LoopDevice::open("/dev/loop0").unwrap();
+/// let err = ld.try_set_offset(4096).unwrap_err();
+/// if let Some(Error::OffsetChangeUnsupported { .. }) =
+///     err.get_ref().and_then(|err| err.downcast_ref::<Error>())
+/// {
+///     println!("detach and attach again with the new offset");
+/// }
+/// ```
+///
+/// [`io::Error`]: std::io::Error
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The offset of a loop device can only be set when attaching it.
+    OffsetChangeUnsupported {
+        /// Whether the device was attached.
+        attached: bool,
+    },
+    /// The loop control device does not exist, usually because the `loop` module is not
+    /// loaded.
+    ControlDeviceNotFound {
+        /// Path of the loop control device.
+        path: PathBuf,
+    },
+    /// The loop control device can only be opened by root or with `CAP_SYS_ADMIN`.
+    ControlDevicePermissionDenied {
+        /// Path of the loop control device.
+        path: PathBuf,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OffsetChangeUnsupported { attached: true } => f.write_str(
+                "the offset of an attached loop device can not be changed, detach it and attach it again",
+            ),
+            Self::OffsetChangeUnsupported { attached: false } => {
+                f.write_str("the offset of a loop device can only be set when attaching it")
+            }
+            Self::ControlDeviceNotFound { path } => write!(
+                f,
+                "{} does not exist, load the loop module with `modprobe loop`",
+                path.display()
+            ),
+            Self::ControlDevicePermissionDenied { path } => write!(
+                f,
+                "permission denied opening {}, run as root or with CAP_SYS_ADMIN",
+                path.display()
+            ),
+        }
     }
 }
+
+impl std::error::Error for Error {}
@@ -0,0 +1,4016 @@
+//! Loop device support on Linux and Android.
+use crate::{AttachConfig, Geometry, LoopDeviceInfo, LoopFlags, LoopInfo, LoopSnapshot};
+#[cfg(feature = "direct_io")]
+use bindings::LOOP_SET_DIRECT_IO;
+use bindings::{
+    loop_info64, LOOP_CLR_FD, LOOP_CTL_ADD, LOOP_CTL_GET_FREE, LOOP_CTL_REMOVE, LOOP_GET_STATUS64,
+    LOOP_SET_BLOCK_SIZE, LOOP_SET_CAPACITY, LOOP_SET_FD, LOOP_SET_STATUS64, LO_FLAGS_AUTOCLEAR,
+    LO_FLAGS_DIRECT_IO, LO_FLAGS_PARTSCAN, LO_FLAGS_READ_ONLY,
+};
+use libc::{c_int, ioctl};
+use std::{
+    default::Default,
+    error::Error,
+    ffi::{CString, OsStr, OsString},
+    fmt,
+    fs::{File, OpenOptions},
+    io, mem,
+    ops::Deref,
+    os::unix::prelude::*,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+#[allow(non_camel_case_types)]
+#[allow(dead_code)]
+#[allow(non_snake_case)]
+mod bindings {
+    include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+}
+
+mod platform;
+use platform::LOOP_CONTROL;
+
+#[cfg(feature = "log")]
+use log::{debug, warn};
+
+// Without the `log` feature the arguments are still type checked but never evaluated.
+#[cfg(not(feature = "log"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+#[cfg(not(feature = "log"))]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+
+/// Issue an ioctl on the file descriptor of `$file` and convert a failure into an `io::Error`.
+/// With the `log` feature every ioctl is logged at debug level.
+macro_rules! loop_ioctl {
+    ($file:expr, $request:ident) => {
+        loop_ioctl!($file, $request, 0)
+    };
+    ($file:expr, $request:ident, $arg:expr) => {{
+        let fd = $file.as_raw_fd() as c_int;
+        let arg = $arg;
+        debug!(
+            "{} on {} with {:?}",
+            stringify!($request),
+            fd_path(fd).display(),
+            arg
+        );
+        ioctl_to_error(unsafe { ioctl(fd, $request as IoctlRequest, arg) })
+    }};
+}
+
+#[cfg(all(not(target_os = "android"), not(target_env = "musl")))]
+type IoctlRequest = libc::c_ulong;
+#[cfg(any(target_os = "android", target_env = "musl"))]
+type IoctlRequest = libc::c_int;
+
+// Block device ioctls from `linux/fs.h`. These are defined with the `_IO` macros which bindgen
+// cannot expand.
+const BLKROGET: u32 = 0x125e;
+const BLKRRPART: u32 = 0x125f;
+const BLKRASET: u32 = 0x1262;
+const BLKRAGET: u32 = 0x1263;
+const BLKSSZGET: u32 = 0x1268;
+const BLKPBSZGET: u32 = 0x127b;
+
+// `LOOP_CONFIGURE` and its argument were added in Linux 5.8, they are defined here so the crate
+// still builds against older kernel headers.
+const LOOP_CONFIGURE: u32 = 0x4c0a;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct loop_config {
+    fd: u32,
+    block_size: u32,
+    info: loop_info64,
+    reserved: [u64; 8],
+}
+
+// Whether the kernel supports `LOOP_CONFIGURE`, set by the first successful probe.
+static CONFIGURE_SUPPORTED: OnceLock<bool> = OnceLock::new();
+
+// The major number of loop devices, `LOOP_MAJOR` in `linux/major.h`.
+const LOOP_MAJOR: u32 = 7;
+
+// How long to wait for the old backing file to be released when replacing it.
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(2);
+// `_IOR(0x12, 114, size_t)`
+const BLKGETSIZE64: u32 = 0x8000_1272 | (mem::size_of::<usize>() as u32) << 16;
+
+/// Interface to the loop control device: `/dev/loop-control`.
+#[derive(Debug)]
+pub struct LoopControl {
+    dev_file: File,
+}
+
+impl LoopControl {
+    /// Opens the loop control device.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when opening
+    /// the loop control file `/dev/loop-control`. See
+    /// [`OpenOptions::open`](https://doc.rust-lang.org/std/fs/struct.OpenOptions.html)
+    /// for further details.
+    /// If the file does not exist or may not be opened, the inner error is a
+    /// [`crate::Error::ControlDeviceNotFound`] or [`crate::Error::ControlDevicePermissionDenied`]
+    /// explaining how to fix it.
+    pub fn open() -> io::Result<Self> {
+        Self::open_at(LOOP_CONTROL)
+    }
+
+    /// Opens the loop control device at `path` instead of `/dev/loop-control`, ie when it is
+    /// bind mounted elsewhere in a container or mount namespace.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open_at("/run/dev/loop-control").unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when opening
+    /// the loop control file `path`. See
+    /// [`OpenOptions::open`](https://doc.rust-lang.org/std/fs/struct.OpenOptions.html)
+    /// for further details.
+    /// If the file does not exist or may not be opened, the inner error is a
+    /// [`crate::Error::ControlDeviceNotFound`] or [`crate::Error::ControlDevicePermissionDenied`]
+    /// explaining how to fix it.
+    pub fn open_at<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        Ok(Self {
+            dev_file: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .map_err(|err| control_open_error(path, err))?,
+        })
+    }
+
+    /// Opens the loop control device, choosing whether the handle is closed on `exec`.
+    ///
+    /// [`open`](LoopControl::open) always sets `O_CLOEXEC`. Passing `cloexec = false` lets
+    /// the handle be inherited by child processes, eg to hand it to a helper started with
+    /// [`Command`](std::process::Command).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open_with_flags(false).unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for the same reasons as
+    /// [`open`](LoopControl::open), or if the close-on-exec flag could not be changed.
+    pub fn open_with_flags(cloexec: bool) -> io::Result<Self> {
+        let path = Path::new(LOOP_CONTROL);
+        Ok(Self {
+            dev_file: open_with_cloexec(path, cloexec)
+                .map_err(|err| control_open_error(path, err))?,
+        })
+    }
+
+    /// Opens the loop control device for reading only.
+    ///
+    /// This works on systems where `/dev/loop-control` is readable but not writable by the
+    /// caller, ie for monitoring tools that only [`probe`](LoopControl::probe) the devices.
+    /// Requests that allocate devices like [`next_free`](LoopControl::next_free) and
+    /// [`add`](LoopControl::add) are not meant for such a handle: whether the kernel accepts
+    /// them depends on its version and the permissions of the loop device nodes, so they should
+    /// be expected to fail.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open_read_only().unwrap();
+    /// for device in lc.probe().unwrap() {
+    ///     println!("{}", device.path.display());
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for the same reasons as
+    /// [`open`](LoopControl::open).
+    pub fn open_read_only() -> io::Result<Self> {
+        let path = Path::new(LOOP_CONTROL);
+        Ok(Self {
+            dev_file: OpenOptions::new()
+                .read(true)
+                .open(path)
+                .map_err(|err| control_open_error(path, err))?,
+        })
+    }
+
+    /// Opens the loop control device, waiting for it to appear.
+    ///
+    /// Early during boot `/dev/loop-control` may not have been created yet. This retries
+    /// opening it with a short backoff until it exists or `timeout` elapses.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// use std::time::Duration;
+    /// let lc = LoopControl::open_timeout(Duration::from_secs(5)).unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for the same reasons as
+    /// [`open`](LoopControl::open), other than the device not existing. An error of kind
+    /// [`TimedOut`](io::ErrorKind::TimedOut) is returned if the device still does not exist
+    /// after `timeout`.
+    pub fn open_timeout(timeout: Duration) -> io::Result<Self> {
+        let start = Instant::now();
+        let mut backoff = Duration::from_millis(10);
+        loop {
+            match Self::open() {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    let elapsed = start.elapsed();
+                    if elapsed >= timeout {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("timed out waiting for {} to appear", LOOP_CONTROL),
+                        ));
+                    }
+                    thread::sleep(backoff.min(timeout - elapsed));
+                    backoff = (backoff * 2).min(Duration::from_millis(250));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Finds and opens the next available loop device.
+    ///
+    /// If the device has no node in `/dev`, ie in a container without devtmpfs, the node is
+    /// created with the device number the kernel lists in sysfs.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open().unwrap();
+    /// let ld = lc.next_free().unwrap();
+    /// println!("{}", ld.path().unwrap().display());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when opening
+    /// the loop device file `/dev/loopX`. See
+    /// [`OpenOptions::open`](https://doc.rust-lang.org/std/fs/struct.OpenOptions.html)
+    /// for further details. If the node of the free device does not exist and can not be
+    /// created either, an error of kind [`NotFound`](io::ErrorKind::NotFound) naming the device
+    /// is returned.
+    pub fn next_free(&self) -> io::Result<LoopDevice> {
+        self.next_free_with_number().map(|(_, ld)| ld)
+    }
+
+    /// Finds and opens the next available loop device like [`next_free`](LoopControl::next_free),
+    /// also returning the number of the device the kernel reported.
+    ///
+    /// Unlike parsing it out of [`LoopDevice::path`], this also works without `/proc`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open().unwrap();
+    /// let (number, ld) = lc.next_free_with_number().unwrap();
+    /// println!("loop{} is free", number);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for the same reasons as
+    /// [`next_free`](LoopControl::next_free).
+    pub fn next_free_with_number(&self) -> io::Result<(u32, LoopDevice)> {
+        let number = self.find_free_number()?;
+        let ld = match LoopDevice::open_number(number) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                // `LOOP_CTL_GET_FREE` created the device in the kernel, only the node is missing,
+                // ie in containers without devtmpfs.
+                create_device_node(number)
+                    .and_then(|_| LoopDevice::open_number(number))
+                    .map_err(|create_err| {
+                        io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!(
+                                "kernel reported free device {} but its node could not be \
+                                 created: {}",
+                                number, create_err
+                            ),
+                        )
+                    })
+            }
+            result => result,
+        }?;
+        Ok((number, ld))
+    }
+
+    /// Finds and opens the next available loop device while holding an exclusive advisory lock
+    /// on the loop control device, which is kept until the returned [`LockedLoopDevice`] is
+    /// dropped.
+    ///
+    /// Finding a free device and attaching it is not atomic, the kernel keeps handing out the
+    /// same device until it is attached. Attach the device before dropping the guard: another
+    /// caller of this method, in this or any other process, blocks until then and so is handed
+    /// a different device. Every call takes the lock on its own open file, so this also holds
+    /// between threads sharing this `LoopControl`. It offers no protection against callers that
+    /// do not take the lock, such as [`next_free`](LoopControl::next_free) or `losetup`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open().unwrap();
+    /// let ld = lc.next_free_locked().unwrap();
+    /// ld.attach_file("disk.img").unwrap();
+    /// let ld = ld.into_inner();
+    /// println!("{}", ld.path().unwrap().display());
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the loop control device can not be opened again
+    /// through `/proc/self/fd` to take the lock, if the lock can not be taken or for the same
+    /// reasons as [`next_free`](LoopControl::next_free).
+    pub fn next_free_locked(&self) -> io::Result<LockedLoopDevice> {
+        // `flock` locks the open file, so the lock needs a file of its own to keep out other
+        // threads that share `dev_file`.
+        let lock = File::open(format!("/proc/self/fd/{}", self.dev_file.as_raw_fd()))?;
+        ioctl_to_error(unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) })?;
+        Ok(LockedLoopDevice {
+            device: self.next_free()?,
+            _lock: lock,
+        })
+    }
+
+    /// Finds and opens `count` distinct available loop devices, adding new devices as needed.
+    ///
+    /// The kernel keeps handing out the same free device until it is attached, so asking for
+    /// the next free device in a loop returns the same device again. This instead walks up from
+    /// the first free device and takes every unattached one, creating missing devices on the
+    /// way. The devices are only guaranteed to be distinct from each other, another process
+    /// may still attach one of them first.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open().unwrap();
+    /// for ld in lc.next_free_n(4).unwrap() {
+    ///     println!("{}", ld.path().unwrap().display());
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the ioctls to find
+    /// or add a free loop device, or when opening one of them. Devices that were already opened
+    /// are closed again. An error of kind [`NotFound`](io::ErrorKind::NotFound) is returned if
+    /// the device numbers run out before `count` free devices were found.
+    pub fn next_free_n(&self, count: usize) -> io::Result<Vec<LoopDevice>> {
+        let mut devices = Vec::with_capacity(count);
+        if count == 0 {
+            return Ok(devices);
+        }
+        let mut number = self.find_free_number()?;
+        loop {
+            let device = self.open_device(number)?;
+            if !device.is_attached()? {
+                devices.push(device);
+            }
+            if devices.len() == count {
+                return Ok(devices);
+            }
+            number = number.checked_add(1).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "no free loop device found")
+            })?;
+        }
+    }
+
+    /// Finds and opens the first available loop device numbered `min` or above, adding it if
+    /// needed.
+    ///
+    /// Unlike [`next_free`](LoopControl::next_free) this does not rely on the kernel to pick
+    /// the device, so low numbered devices reserved by the host can be skipped. As with
+    /// `next_free` another process may still attach the device before it is used.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open().unwrap();
+    /// let ld = lc.next_free_from(16).unwrap();
+    /// println!("{}", ld.path().unwrap().display());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when opening or adding one of the
+    /// probed loop devices, or when calling the ioctl to get its status.
+    pub fn next_free_from(&self, min: impl Into<DeviceNumber>) -> io::Result<LoopDevice> {
+        let mut number = min.into().0;
+        loop {
+            let device = self.open_device(number)?;
+            if !device.is_attached()? {
+                return Ok(device);
+            }
+            number = number.checked_add(1).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "no free loop device found")
+            })?;
+        }
+    }
+
+    /// Finds the number of the next available loop device without opening it.
+    ///
+    /// The number is only advisory, another process may take the device before it is opened.
+    /// Use [`next_free`](LoopControl::next_free) to find and open a device in one go.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open().unwrap();
+    /// println!("/dev/loop{}", lc.find_free_number().unwrap());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to find a free loop device.
+    pub fn find_free_number(&self) -> io::Result<u32> {
+        let dev_num = loop_ioctl!(self.dev_file, LOOP_CTL_GET_FREE)?;
+        Ok(dev_num as u32)
+    }
+
+    /// Whether the kernel supports `LOOP_CONFIGURE`, which attaches a backing file and sets the
+    /// status and block size of a device in a single atomic step. It was added in Linux 5.8.
+    ///
+    /// The kernel is probed by issuing `LOOP_CONFIGURE` with an invalid file descriptor on an
+    /// existing loop device, no device is created for the probe. `EBADF` means the request is
+    /// known, `ENOTTY` or `EINVAL` means it is not. The answer is cached for the lifetime of the
+    /// process, `false` is returned without caching it if the probe itself fails, eg because
+    /// there is no loop device yet or none could be opened.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open().unwrap();
+    /// if lc.supports_configure() {
+    ///     println!("attaching is atomic");
+    /// }
+    /// ```
+    pub fn supports_configure(&self) -> bool {
+        if let Some(&supported) = CONFIGURE_SUPPORTED.get() {
+            return supported;
+        }
+        let probed = loop_numbers()
+            .and_then(|numbers| {
+                numbers
+                    .into_iter()
+                    .find_map(|n| LoopDevice::open_number(n).ok())
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "no loop device to probe")
+                    })
+            })
+            .and_then(|ld| ld.supports_configure());
+        match probed {
+            Ok(supported) => supported,
+            Err(err) => {
+                debug!("could not probe for LOOP_CONFIGURE: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Report every loop device with its status, size and holders, ordered by number.
+    ///
+    /// Devices that are not attached are listed with an empty status. A device that fails to
+    /// be probed does not stop the others from being reported, the error is recorded in its
+    /// [`error`](LoopDeviceInfo::error) instead with the fields probed so far.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open().unwrap();
+    /// for device in lc.probe().unwrap() {
+    ///     match (device.error, device.info.backing_file) {
+    ///         (Some(err), _) => println!("{}: {}", device.path.display(), err),
+    ///         (None, Some(file)) => println!("{}: {}", device.path.display(), file.display()),
+    ///         (None, None) => println!("{}: free", device.path.display()),
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if listing the loop devices in `/sys/block` fails.
+    pub fn probe(&self) -> io::Result<Vec<LoopDeviceInfo>> {
+        Ok(loop_numbers()?.into_iter().map(probe_device).collect())
+    }
+
+    /// List every loop device with its number and whether it is attached, ordered by number.
+    ///
+    /// Unlike [`probe`](LoopControl::probe) this only reads `/sys/block` and does not open the
+    /// devices, so it is cheap and works without access to them. Devices removed while iterating
+    /// are skipped.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open().unwrap();
+    /// for device in lc.list().unwrap().filter(|device| device.attached) {
+    ///     println!("{} is attached", device.number);
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if listing the loop devices in `/sys/block` fails.
+    pub fn list(&self) -> io::Result<LoopDevices> {
+        Ok(LoopDevices {
+            numbers: loop_numbers()?.into_iter(),
+        })
+    }
+
+    /// Detaches every attached loop device that is not mounted, returning the result for each
+    /// attached device ordered by number.
+    ///
+    /// Unlike [`detach_all`](crate::detach_all) this does not stop at the first device that
+    /// fails, so a partial cleanup can be inspected. Devices where the device itself or one of
+    /// its partitions shows up in `/proc/self/mountinfo` are left alone and reported with an
+    /// error of kind [`ResourceBusy`](io::ErrorKind::ResourceBusy). A device that is detached
+    /// by someone else in the meantime counts as detached. Note that this is not limited to
+    /// devices attached by this process.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open().unwrap();
+    /// for (number, result) in lc.detach_all().unwrap() {
+    ///     if let Err(err) = result {
+    ///         eprintln!("could not detach {}: {}", number, err);
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if listing the loop devices in `/sys/block` fails.
+    pub fn detach_all(&self) -> io::Result<Vec<(DeviceNumber, io::Result<()>)>> {
+        Ok(self
+            .list()?
+            .filter(|device| device.attached)
+            .map(|device| (device.number, detach_unmounted(&device)))
+            .collect())
+    }
+
+    /// Add and opens a new loop device.
+    ///
+    /// If attaching the returned device fails and it is dropped while still detached, the device
+    /// is removed again (with `LOOP_CTL_REMOVE`) so that failed attempts do not leave empty
+    /// devices behind. This also applies to devices added by [`next_free`](LoopControl::next_free)
+    /// and [`open_device`](LoopControl::open_device).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open().unwrap();
+    /// let ld = lc.add(1).unwrap();
+    /// println!("{}", ld.path().unwrap().display());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This funcitons will return an error when a loop device with the passed
+    /// number exists or opening the newly created device fails.
+    pub fn add(&self, n: impl Into<DeviceNumber>) -> io::Result<LoopDevice> {
+        let dev_num = loop_ioctl!(self.dev_file, LOOP_CTL_ADD, n.into().0 as c_int)?;
+        let mut ld = LoopDevice::open_number(dev_num as u32)?;
+        ld.added = Some(dev_num as u32);
+        Ok(ld)
+    }
+
+    /// Removes the loop device with the given number, the reverse of [`add`](LoopControl::add).
+    ///
+    /// The device must be detached and no longer opened by anyone, so drop every
+    /// [`LoopDevice`] of it first.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open().unwrap();
+    /// drop(lc.add(16).unwrap());
+    /// lc.remove(16).unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the ioctl to remove
+    /// the device. An error of kind [`ResourceBusy`](io::ErrorKind::ResourceBusy) is returned if
+    /// the device is attached or still open, and one of kind
+    /// [`NotFound`](io::ErrorKind::NotFound) if it does not exist.
+    pub fn remove(&self, n: impl Into<DeviceNumber>) -> io::Result<()> {
+        let number = n.into();
+        loop_ioctl!(self.dev_file, LOOP_CTL_REMOVE, number.0 as c_int)
+            .map(drop)
+            .map_err(|err| match err.raw_os_error() {
+                Some(libc::EBUSY) => io::Error::new(
+                    io::ErrorKind::ResourceBusy,
+                    format!(
+                        "{} is attached or still open, detach it and close every handle to it: {}",
+                        number, err
+                    ),
+                ),
+                Some(libc::ENODEV) => io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} does not exist: {}", number, err),
+                ),
+                _ => err,
+            })
+    }
+
+    /// Opens the loop device with the given number, adding it through this loop control device
+    /// if it does not exist yet.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open().unwrap();
+    /// for n in 16..32 {
+    ///     let ld = lc.open_device(n).unwrap();
+    ///     println!("{}", ld.path().unwrap().display());
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when opening the loop device
+    /// file or when calling the ioctl to add the device.
+    pub fn open_device(&self, n: impl Into<DeviceNumber>) -> io::Result<LoopDevice> {
+        let n = n.into();
+        match LoopDevice::open_number(n) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => self.add(n),
+            result => result,
+        }
+    }
+
+    /// Get the `max_loop` parameter of the loop driver, `None` when it is `0` which means devices
+    /// are allocated dynamically.
+    ///
+    /// Depending on the kernel version `max_loop` either caps the number of loop devices or only
+    /// sets how many devices are created when the driver is loaded.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// if let Some(max) = LoopControl::max_devices().unwrap() {
+    ///     println!("at most {} loop devices", max);
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error when `/sys/module/loop/parameters/max_loop` can not
+    /// be read or does not contain a number.
+    pub fn max_devices() -> io::Result<Option<u32>> {
+        let max_loop = std::fs::read_to_string(platform::MAX_LOOP)?;
+        let max_loop = max_loop.trim().parse::<u32>().map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid {}: {}", platform::MAX_LOOP, err),
+            )
+        })?;
+        Ok(Some(max_loop).filter(|&max| max > 0))
+    }
+
+    /// Creates a new independently owned handle to the loop control device, like
+    /// [`File::try_clone`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error when the underlying file descriptor can not be
+    /// duplicated.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            dev_file: self.dev_file.try_clone()?,
+        })
+    }
+
+    /// Wrap the loop control device so that the devices added with
+    /// [`ScopedLoopControl::add`] are removed again when it is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open().unwrap().scoped();
+    /// let ld = lc.add(16).unwrap();
+    /// println!("{}", ld.path().unwrap().display());
+    /// drop(ld);
+    /// drop(lc); // removes /dev/loop16
+    /// ```
+    pub fn scoped(self) -> ScopedLoopControl {
+        ScopedLoopControl {
+            control: self,
+            added: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+/// A loop control device that removes the devices it added when it is dropped. Created with
+/// [`LoopControl::scoped`].
+///
+/// Only devices added with [`add`](ScopedLoopControl::add) are tracked, every other method of
+/// [`LoopControl`] is available through `Deref`. A device can only be removed once it is
+/// detached and every handle to it is closed, so drop the [`LoopDevice`]s first. Devices that
+/// can not be removed are left behind, with a warning if the `log` feature is enabled.
+#[derive(Debug)]
+pub struct ScopedLoopControl {
+    control: LoopControl,
+    added: Mutex<Vec<u32>>,
+}
+
+impl ScopedLoopControl {
+    /// Add and open a new loop device like [`LoopControl::add`], removing it again when this
+    /// is dropped.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for the same reasons as [`LoopControl::add`].
+    pub fn add(&self, n: impl Into<DeviceNumber>) -> io::Result<LoopDevice> {
+        let number = n.into();
+        let ld = self.control.add(number)?;
+        self.added
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(number.0);
+        Ok(ld)
+    }
+}
+
+impl Deref for ScopedLoopControl {
+    type Target = LoopControl;
+
+    fn deref(&self) -> &LoopControl {
+        &self.control
+    }
+}
+
+impl Drop for ScopedLoopControl {
+    fn drop(&mut self) {
+        let added = self.added.get_mut().unwrap_or_else(PoisonError::into_inner);
+        for number in added.drain(..) {
+            if let Err(err) = self.control.remove(number) {
+                warn!("could not remove {}: {}", DeviceNumber(number), err);
+            }
+        }
+    }
+}
+
+/// A free loop device that holds the lock taken by [`LoopControl::next_free_locked`] until it
+/// is dropped or turned into a plain [`LoopDevice`] with
+/// [`into_inner`](LockedLoopDevice::into_inner).
+///
+/// Every method of [`LoopDevice`] is available through `Deref`.
+#[derive(Debug)]
+pub struct LockedLoopDevice {
+    device: LoopDevice,
+    // Closing the file releases the lock.
+    _lock: File,
+}
+
+impl LockedLoopDevice {
+    /// Release the lock and return the device.
+    pub fn into_inner(self) -> LoopDevice {
+        self.device
+    }
+}
+
+impl Deref for LockedLoopDevice {
+    type Target = LoopDevice;
+
+    fn deref(&self) -> &LoopDevice {
+        &self.device
+    }
+}
+
+impl AsRawFd for LoopControl {
+    fn as_raw_fd(&self) -> RawFd {
+        self.dev_file.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for LoopControl {
+    fn into_raw_fd(self) -> RawFd {
+        self.dev_file.into_raw_fd()
+    }
+}
+
+impl FromRawFd for LoopControl {
+    /// Wraps an already open loop control device, ie one inherited from or passed by a more
+    /// privileged process.
+    ///
+    /// # Safety
+    ///
+    /// The fd must be a valid open fd of the loop control device and is owned by the returned
+    /// `LoopControl`, it is closed when the `LoopControl` is dropped.
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self {
+            dev_file: File::from_raw_fd(fd),
+        }
+    }
+}
+
+impl fmt::Display for LoopControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(LOOP_CONTROL)
+    }
+}
+
+/// A loop device listed by [`LoopControl::list`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopDeviceEntry {
+    /// The number of the device.
+    pub number: DeviceNumber,
+    /// Whether the device is attached to a backing file.
+    pub attached: bool,
+}
+
+impl LoopDeviceEntry {
+    /// The path of the device, ie `/dev/loop3`.
+    pub fn path(&self) -> PathBuf {
+        self.number.path()
+    }
+
+    /// Opens the device.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for the same reasons as [`LoopDevice::open`].
+    pub fn open(&self) -> io::Result<LoopDevice> {
+        LoopDevice::open_number(self.number)
+    }
+}
+
+/// Iterator over the loop devices in `/sys/block`. Created with [`LoopControl::list`].
+#[derive(Debug)]
+pub struct LoopDevices {
+    numbers: std::vec::IntoIter<u32>,
+}
+
+impl Iterator for LoopDevices {
+    type Item = LoopDeviceEntry;
+
+    fn next(&mut self) -> Option<LoopDeviceEntry> {
+        loop {
+            let number = self.numbers.next()?;
+            let device_dir = platform::sysfs_device_dir(number);
+            if !device_dir.exists() {
+                continue;
+            }
+            // The `loop` directory with the status of the device only exists while attached.
+            return Some(LoopDeviceEntry {
+                number: DeviceNumber(number),
+                attached: device_dir.join("loop").exists(),
+            });
+        }
+    }
+}
+
+/// The number of a loop device, ie `3` for `/dev/loop3`.
+///
+/// Displays as the path of the device, using the prefix of the platform (`/dev/block/loop3` on
+/// Android), and parses from either the number or the path. Every method taking a device number
+/// accepts a `DeviceNumber` or a plain `u32`.
+///
+/// # Examples
+///
+/// ```
+/// use loopdev::DeviceNumber;
+/// let number: DeviceNumber = "3".parse().unwrap();
+/// assert_eq!(number, DeviceNumber(3));
+/// # #[cfg(not(target_os = "android"))]
+/// assert_eq!("/dev/loop3".parse::<DeviceNumber>().unwrap(), number);
+/// # #[cfg(not(target_os = "android"))]
+/// assert_eq!(number.to_string(), "/dev/loop3");
+/// assert!("/dev/sda".parse::<DeviceNumber>().is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DeviceNumber(pub u32);
+
+impl DeviceNumber {
+    /// The path of the device, ie `/dev/loop3`.
+    pub fn path(self) -> PathBuf {
+        platform::device_path(self.0)
+    }
+}
+
+impl From<u32> for DeviceNumber {
+    fn from(number: u32) -> Self {
+        Self(number)
+    }
+}
+
+impl From<DeviceNumber> for u32 {
+    fn from(number: DeviceNumber) -> Self {
+        number.0
+    }
+}
+
+impl fmt::Display for DeviceNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path().display())
+    }
+}
+
+impl std::str::FromStr for DeviceNumber {
+    type Err = io::Error;
+
+    /// Parses a device number, ie `3`, or the path of a loop device, ie `/dev/loop3`.
+    fn from_str(s: &str) -> io::Result<Self> {
+        s.parse()
+            .ok()
+            .or_else(|| platform::device_number(Path::new(s)))
+            .map(Self)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is not a loop device number or path", s),
+                )
+            })
+    }
+}
+
+/// Interface to a loop device ie `/dev/loop0`.
+///
+/// Dropping a `LoopDevice` closes the device but does not detach it, either detach it or attach
+/// it with [`autoclear`](AttachOptions::autoclear). With the `leak_check` feature a warning is
+/// logged when a handle that attached the device is dropped while the device is still attached
+/// without autoclear. Handles that only opened or looked at a device never warn. A device added by
+/// [`LoopControl::add`] whose attach failed is removed again when it is dropped detached.
+///
+/// Two `LoopDevice`s are equal when they refer to the same device node, see
+/// [`eq`](LoopDevice::eq).
+#[derive(Debug)]
+pub struct LoopDevice {
+    device: mem::ManuallyDrop<File>,
+    // The number `LoopControl::add` returned when this handle added the device.
+    added: Option<u32>,
+    // Set when attaching the added device failed and it has not been attached since.
+    failed_attach: AtomicBool,
+    // Set when the device was attached through this handle, for the leak check.
+    attached_here: AtomicBool,
+}
+
+impl AsRawFd for LoopDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.device.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for LoopDevice {
+    fn into_raw_fd(self) -> RawFd {
+        // The fd is handed over, so neither close it nor run the leak check.
+        mem::ManuallyDrop::new(self).device.as_raw_fd()
+    }
+}
+
+impl FromRawFd for LoopDevice {
+    /// Wraps an already open loop device.
+    ///
+    /// # Safety
+    ///
+    /// The fd must be a valid open fd of a loop device and is owned by the returned
+    /// `LoopDevice`, it is closed when the device is dropped.
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self::from_file(File::from_raw_fd(fd))
+    }
+}
+
+impl TryFrom<&Path> for LoopDevice {
+    type Error = io::Error;
+
+    /// Opens a loop device, checking that the path names a loop device ie `/dev/loop0` and that
+    /// it is a block device. Use this over [`LoopDevice::open`] for user supplied paths.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// use std::path::Path;
+    /// let ld = LoopDevice::try_from(Path::new("/dev/loop0")).unwrap();
+    /// assert!(LoopDevice::try_from(Path::new("/dev/sda")).is_err());
+    /// ```
+    fn try_from(path: &Path) -> io::Result<Self> {
+        if platform::device_number(path).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a loop device", path.display()),
+            ));
+        }
+        let device = Self::open(path)?;
+        if !device.device.metadata()?.file_type().is_block_device() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a block device", path.display()),
+            ));
+        }
+        Ok(device)
+    }
+}
+
+impl PartialEq for LoopDevice {
+    /// Compares the device numbers (`rdev`) of both handles, so two handles to `/dev/loop3`
+    /// are equal even when opened separately. Equality is by device node identity, not by
+    /// backing file: two devices attached to the same file are not equal. Handles whose
+    /// metadata cannot be read are never equal.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld0 = LoopDevice::open("/dev/loop0").unwrap();
+    /// assert!(ld0 == LoopDevice::open("/dev/loop0").unwrap());
+    /// assert!(ld0 != LoopDevice::open("/dev/loop1").unwrap());
+    /// ```
+    fn eq(&self, other: &Self) -> bool {
+        match (self.device.metadata(), other.device.metadata()) {
+            (Ok(this), Ok(other)) => this.rdev() == other.rdev(),
+            _ => false,
+        }
+    }
+}
+
+/// Reads the data the device exposes, ie the slice of the backing file selected by the offset
+/// and size limit, through the kernel loop driver. The read position is that of the underlying
+/// device handle and is shared with handles created by [`try_clone`](LoopDevice::try_clone).
+///
+/// # Examples
+///
+/// ```no_run
+/// use loopdev::LoopDevice;
+/// use std::io::Read;
+/// let mut ld = LoopDevice::open("/dev/loop0").unwrap();
+/// ld.with().offset(512).attach("disk.img").unwrap();
+/// let mut first_sector = [0; 512];
+/// ld.read_exact(&mut first_sector).unwrap();
+/// # ld.detach().unwrap();
+/// ```
+impl io::Read for LoopDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.device.read(buf)
+    }
+}
+
+/// Moves the read position within the data the device exposes, position `0` is at the offset
+/// into the backing file.
+impl io::Seek for LoopDevice {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.device.seek(pos)
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        #[cfg(feature = "leak_check")]
+        if self.attached_here.load(Ordering::Relaxed) {
+            if let Ok(info) = self.info() {
+                if info.lo_flags & LO_FLAGS_AUTOCLEAR == 0 {
+                    warn!(
+                        "{} was dropped while attached without autoclear, it stays attached",
+                        self
+                    );
+                }
+            }
+        }
+        let remove = self
+            .added
+            .filter(|_| self.failed_attach.load(Ordering::Relaxed))
+            .filter(|_| matches!(self.is_attached(), Ok(false)));
+        // The kernel only removes a device that nobody has open, so close it first. Safe as the
+        // device is not used after this.
+        unsafe { mem::ManuallyDrop::drop(&mut self.device) };
+        if let Some(number) = remove {
+            if let Err(err) = LoopControl::open().and_then(|lc| lc.remove(number)) {
+                warn!(
+                    "failed to remove loop device {} after a failed attach: {}",
+                    number, err
+                );
+            }
+        }
+    }
+}
+
+impl fmt::Display for LoopDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.path() {
+            Some(path) => write!(f, "{}", path.display()),
+            None => f.write_str("<unknown loop device>"),
+        }
+    }
+}
+
+impl LoopDevice {
+    fn from_file(device: File) -> Self {
+        Self {
+            device: mem::ManuallyDrop::new(device),
+            added: None,
+            failed_attach: AtomicBool::new(false),
+            attached_here: AtomicBool::new(false),
+        }
+    }
+
+    /// Opens a loop device.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when opening
+    /// the given loop device file. See
+    /// [`OpenOptions::open`](https://doc.rust-lang.org/std/fs/struct.OpenOptions.html)
+    /// for further details.
+    pub fn open<P: AsRef<Path>>(dev: P) -> io::Result<Self> {
+        // TODO create dev if it does not exist and is a loop device path
+        Ok(Self::from_file(
+            OpenOptions::new().read(true).write(true).open(dev)?,
+        ))
+    }
+
+    /// Opens a loop device, choosing whether the handle is closed on `exec`.
+    ///
+    /// [`open`](LoopDevice::open) always sets `O_CLOEXEC`. Passing `cloexec = false` lets
+    /// the handle be inherited by child processes.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open_with_flags("/dev/loop0", false).unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for the same reasons as
+    /// [`open`](LoopDevice::open), or if the close-on-exec flag could not be changed.
+    pub fn open_with_flags<P: AsRef<Path>>(dev: P, cloexec: bool) -> io::Result<Self> {
+        Ok(Self::from_file(open_with_cloexec(dev.as_ref(), cloexec)?))
+    }
+
+    /// Opens a loop device by its number, ie `0` for `/dev/loop0`.
+    ///
+    /// The path of the device is built with the prefix of the platform so this also opens
+    /// `/dev/block/loop0` on Android.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open_number(0).unwrap();
+    /// # ld.attach_file("disk.img").unwrap();
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when opening
+    /// the loop device file. See
+    /// [`OpenOptions::open`](https://doc.rust-lang.org/std/fs/struct.OpenOptions.html)
+    /// for further details.
+    pub fn open_number(n: impl Into<DeviceNumber>) -> io::Result<Self> {
+        Self::open(n.into().path())
+    }
+
+    /// Creates a new independently owned handle to the same loop device, like
+    /// [`File::try_clone`]. This allows using the device from several threads without sharing
+    /// the `LoopDevice`.
+    ///
+    /// Both handles refer to the same device, attaching or detaching through either one affects
+    /// the device for both.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// use std::thread;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// let worker = ld.try_clone().unwrap();
+    /// thread::spawn(move || worker.attach_file("disk.img").unwrap())
+    ///     .join()
+    ///     .unwrap();
+    /// println!("{:?}", ld.status().unwrap());
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error when the underlying file descriptor can not be
+    /// duplicated.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self::from_file(self.device.try_clone()?))
+    }
+
+    /// Consumes the `LoopDevice` and returns the `File` of the device, ie to read and write the
+    /// device directly or to hand it to an API that takes a `File`. Like
+    /// [`into_raw_fd`](IntoRawFd::into_raw_fd) this does not detach the device.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// # ld.attach_file("disk.img").unwrap();
+    /// let file = ld.into_file();
+    /// println!("{} bytes", file.metadata().unwrap().len());
+    /// ```
+    pub fn into_file(self) -> File {
+        // Safe as `into_raw_fd` hands over ownership of the fd.
+        unsafe { File::from_raw_fd(self.into_raw_fd()) }
+    }
+
+    /// Attach `target` to `backing_file` configured like this device, with the same offset,
+    /// size limit, flags and logical block size. This sets up a second device for failover or
+    /// migration that exposes the same data as this one.
+    ///
+    /// Only the settable fields of the status are copied. The device number and the fields
+    /// describing the backing file (its device and inode numbers) are set by the kernel for
+    /// `target`, and the legacy encryption settings are not copied as current kernels no longer
+    /// support them.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// use std::path::Path;
+    /// let ld0 = LoopDevice::open("/dev/loop0").unwrap();
+    /// let ld1 = LoopDevice::open("/dev/loop1").unwrap();
+    /// ld0.clone_config_to(&ld1, Path::new("replica.img")).unwrap();
+    /// # ld1.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the ioctls to get
+    /// the status and block size of this device, ie `ENXIO` when it is not attached, or for the
+    /// same reasons as [`attach_with_config`](LoopDevice::attach_with_config) when attaching
+    /// `target`.
+    pub fn clone_config_to(&self, target: &LoopDevice, backing_file: &Path) -> io::Result<()> {
+        let info = self.status()?;
+        let config = AttachConfig::new()
+            .offset(info.offset)
+            .size_limit(info.size_limit)
+            .flags(info.flags())
+            .block_size(self.block_size()?);
+        target.attach_with_config(backing_file, &config)
+    }
+
+    /// Attach the loop device to a file with given options.
+    ///
+    /// # Examples
+    ///
+    /// Attach the device to a file.
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let mut ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// ld.with().part_scan(true).attach("disk.img").unwrap();
+    /// # ld.detach().unwrap();
+    /// ```
+    pub fn with(&self) -> AttachOptions<'_> {
+        AttachOptions {
+            device: self,
+            config: AttachConfig::default(),
+        }
+    }
+
+    /// Change the options of an attached device that the kernel allows to change while it is in
+    /// use. Options that are not set keep their current value.
+    ///
+    /// # Examples
+    ///
+    /// Let the device detach once it is closed and drop its size limit.
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// # ld.attach_file("disk.img").unwrap();
+    /// ld.update().autoclear(true).size_limit(0).apply().unwrap();
+    /// ```
+    pub fn update(&self) -> UpdateOptions<'_> {
+        UpdateOptions {
+            device: self,
+            offset: None,
+            size_limit: None,
+            autoclear: None,
+            #[cfg(feature = "direct_io")]
+            direct_io: None,
+        }
+    }
+
+    /// Attach the loop device to a file that maps to the whole file.
+    ///
+    /// # Examples
+    ///
+    /// Attach the device to a file.
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// ld.attach_file("disk.img").unwrap();
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons. Either when
+    /// opening the backing file (see
+    /// [`OpenOptions::open`](https://doc.rust-lang.org/std/fs/struct.OpenOptions.html)
+    /// for further details) or when calling the ioctl to attach the backing
+    /// file to the device.
+    pub fn attach_file<P: AsRef<Path>>(&self, backing_file: P) -> io::Result<()> {
+        self.attach_with_config(backing_file, &AttachConfig::default())
+    }
+
+    /// Attach the loop device to the `len` bytes of a file starting at `offset`.
+    ///
+    /// This is a shorthand for setting the [`offset`](AttachOptions::offset) and
+    /// [`size_limit`](AttachOptions::size_limit) with [`with`](LoopDevice::with).
+    ///
+    /// # Examples
+    ///
+    /// Attach the device to the second MiB of a file.
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// ld.attach_range("disk.img", 1024 * 1024, 1024 * 1024).unwrap();
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for the same reasons as
+    /// [`attach_with_config`](LoopDevice::attach_with_config), ie an error of kind
+    /// [`InvalidInput`](io::ErrorKind::InvalidInput) if `offset + len` overflows.
+    pub fn attach_range<P: AsRef<Path>>(
+        &self,
+        backing_file: P,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<()> {
+        self.attach_with_config(
+            backing_file,
+            &AttachConfig::new().offset(offset).size_limit(len),
+        )
+    }
+
+    /// Attach the loop device to a file with the options of `config`.
+    ///
+    /// The backing file is opened read only if the read only flag is set. Otherwise, like
+    /// `losetup`, it falls back to attaching read only when the file cannot be opened for writing.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::{AttachConfig, LoopDevice};
+    /// let config = AttachConfig::new().offset(1024 * 1024);
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// ld.attach_with_config("disk.img", &config).unwrap();
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons. Either when
+    /// opening the backing file (see
+    /// [`OpenOptions::open`](https://doc.rust-lang.org/std/fs/struct.OpenOptions.html)
+    /// for further details) or when calling the ioctl to attach the backing
+    /// file to the device. If the device can not be detached again after a
+    /// failed attach, the detach error is the [`source`](Error::source) of the
+    /// inner error.
+    ///
+    /// Options that can never work are rejected with an error of kind
+    /// [`InvalidInput`](io::ErrorKind::InvalidInput) before the device is touched: a block size
+    /// that is not a power of two between 512 and the page size, direct I/O with an offset
+    /// that is not a multiple of 512 bytes, and an offset plus size limit that does not fit in a
+    /// file offset. Read only devices may use direct I/O.
+    pub fn attach_with_config(
+        &self,
+        backing_file: impl AsRef<Path>,
+        config: &AttachConfig,
+    ) -> io::Result<()> {
+        self.attach_path(backing_file.as_ref(), config).map(drop)
+    }
+
+    /// Open the backing file as `config` asks for and attach it, returning the opened file. A
+    /// backing file created for `create_backing` is removed again if attaching fails.
+    fn attach_path(&self, backing_file: &Path, config: &AttachConfig) -> io::Result<File> {
+        validate_config(config)?;
+        let created = match config.create_backing {
+            Some(size) => create_backing_file(backing_file, size)?,
+            None => false,
+        };
+        let result = self.open_and_attach(backing_file, config);
+        let result = self.record_attach(result);
+        if result.is_err() && created {
+            if let Err(err) = std::fs::remove_file(backing_file) {
+                warn!(
+                    "failed to remove {} after a failed attach: {}",
+                    backing_file.display(),
+                    err
+                );
+            }
+        }
+        result
+    }
+
+    /// Remember whether attaching through this handle succeeded, for the leak check, and whether
+    /// attaching a device added by [`LoopControl::add`] failed. Such a device is removed again
+    /// when this handle is dropped while it is still detached, so failed attempts do not leave
+    /// empty devices behind.
+    fn record_attach<T>(&self, result: io::Result<T>) -> io::Result<T> {
+        if result.is_ok() {
+            self.attached_here.store(true, Ordering::Relaxed);
+        }
+        if self.added.is_some() {
+            self.failed_attach.store(result.is_err(), Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// A new handle to the device that was just attached through this one. The new handle takes
+    /// over the leak check, so dropping this one does not warn.
+    fn clone_attached(&self) -> io::Result<LoopDevice> {
+        let device = self.try_clone()?;
+        let attached_here = self.attached_here.swap(false, Ordering::Relaxed);
+        device.attached_here.store(attached_here, Ordering::Relaxed);
+        Ok(device)
+    }
+
+    fn open_and_attach(&self, backing_file: &Path, config: &AttachConfig) -> io::Result<File> {
+        let (bf, read_only) =
+            open_backing_file(backing_file, config.info.read_only, config.info.direct_io)?;
+        let mut info = loop_info64::from(&config.info);
+        if read_only {
+            info.lo_flags |= LO_FLAGS_READ_ONLY;
+        }
+        let real_path = std::fs::canonicalize(backing_file);
+        set_file_name(
+            &mut info,
+            Some(real_path.as_deref().unwrap_or(backing_file)),
+        );
+        self.attach_fd_with_loop_info(bf.as_raw_fd(), info, config)?;
+        Ok(bf)
+    }
+
+    /// Attach the loop device to a fd with `info` and the remaining options of `config`.
+    ///
+    /// With `exclusive` an exclusive `flock` is taken on the backing file first. The kernel holds
+    /// on to the same open file, so the lock is kept until the device is detached.
+    fn attach_fd_with_loop_info(
+        &self,
+        bf: impl AsRawFd,
+        mut info: loop_info64,
+        config: &AttachConfig,
+    ) -> io::Result<()> {
+        let fd = bf.as_raw_fd() as c_int;
+        // Stat the backing file once so every size computation sees the same length.
+        let file_size = backing_file_size(fd)?;
+        if config.size_limit_from_file {
+            info.lo_sizelimit = file_size.saturating_sub(info.lo_offset);
+        }
+        if config.exclusive && unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+            let err = io::Error::last_os_error();
+            return Err(if err.kind() == io::ErrorKind::WouldBlock {
+                io::Error::new(
+                    io::ErrorKind::ResourceBusy,
+                    "the backing file is locked by another user",
+                )
+            } else {
+                err
+            });
+        }
+        let result = self.set_fd_and_status(fd, &info, config);
+        // The backing file may have been opened buffered because it does not support `O_DIRECT`,
+        // then enabling direct I/O fails and the device is detached again.
+        #[cfg(feature = "direct_io")]
+        let result = result.and_then(|_| {
+            if config.info.direct_io {
+                self.set_direct_io(true)
+                    .map_err(|err| self.detach_after_failed_attach(err))
+            } else {
+                Ok(())
+            }
+        });
+        if result.is_err() && config.exclusive {
+            unsafe { libc::flock(fd, libc::LOCK_UN) };
+        }
+        result
+    }
+
+    /// Whether the kernel supports `LOOP_CONFIGURE`, probing for it on this device the first
+    /// time. The probe passes an invalid fd, which the kernel rejects with `EBADF` before looking
+    /// at the device if it knows the request and with `ENOTTY` or `EINVAL` if it does not.
+    fn supports_configure(&self) -> io::Result<bool> {
+        if let Some(&supported) = CONFIGURE_SUPPORTED.get() {
+            return Ok(supported);
+        }
+        let config = loop_config {
+            fd: u32::MAX,
+            ..Default::default()
+        };
+        let supported =
+            match loop_ioctl!(self.device, LOOP_CONFIGURE, &config as *const loop_config) {
+                Err(err) => match err.raw_os_error() {
+                    Some(libc::EBADF) => true,
+                    Some(libc::ENOTTY | libc::EINVAL) => false,
+                    _ => return Err(err),
+                },
+                Ok(_) => true,
+            };
+        Ok(*CONFIGURE_SUPPORTED.get_or_init(|| supported))
+    }
+
+    /// Attach the backing file and set the status of the device.
+    ///
+    /// If the config has a `block_size` it is set before the status so that any partition scan
+    /// requested by `info` uses that logical block size. When the kernel supports
+    /// `LOOP_CONFIGURE` the backing file, block size and status are all set in that one call.
+    /// With `replace` a device that is already attached is detached first.
+    ///
+    /// If configuring the attached device fails it is detached again. Should that fail as well
+    /// the detach error is returned as the source of the original error.
+    fn set_fd_and_status(
+        &self,
+        bf: RawFd,
+        info: &loop_info64,
+        config: &AttachConfig,
+    ) -> io::Result<()> {
+        if let Some(block_size) = config.block_size {
+            if self.supports_configure().unwrap_or(false) {
+                let loop_config = loop_config {
+                    fd: bf as u32,
+                    block_size,
+                    info: *info,
+                    ..Default::default()
+                };
+                match loop_ioctl!(
+                    self.device,
+                    LOOP_CONFIGURE,
+                    &loop_config as *const loop_config
+                ) {
+                    // Replacing goes through `LOOP_SET_FD` below.
+                    Err(err) if config.replace && err.raw_os_error() == Some(libc::EBUSY) => {}
+                    result => return result.map(drop),
+                }
+            }
+        }
+
+        // Attach the file
+        match loop_ioctl!(self.device, LOOP_SET_FD, bf as c_int) {
+            Err(err) if config.replace && err.raw_os_error() == Some(libc::EBUSY) => {
+                debug!("replacing the backing file of {}", self);
+                self.replace_fd(bf, REPLACE_TIMEOUT)?;
+            }
+            result => {
+                result?;
+            }
+        }
+
+        config
+            .block_size
+            .map_or(Ok(()), |block_size| self.set_block_size(block_size))
+            .and_then(|_| loop_ioctl!(self.device, LOOP_SET_STATUS64, info))
+            .map(drop)
+            .map_err(|err| self.detach_after_failed_attach(err))
+    }
+
+    /// Detach the device after configuring it failed with `err`. Should the detach fail as well
+    /// the detach error is returned as the source of `err`.
+    fn detach_after_failed_attach(&self, err: io::Error) -> io::Error {
+        match self.detach() {
+            Ok(()) => err,
+            Err(detach_err) => {
+                warn!(
+                    "failed to detach {} after a failed attach: {}",
+                    self, detach_err
+                );
+                io::Error::new(
+                    err.kind(),
+                    CleanupError {
+                        error: err,
+                        cleanup: detach_err,
+                    },
+                )
+            }
+        }
+    }
+
+    /// Detach the device and attach `bf` once the kernel has released the old backing file.
+    ///
+    /// The wait for the release checks the device through handles of its own, the fd of this
+    /// handle is left alone. The kernel only releases the backing file after every handle to the
+    /// device is closed, so if this handle is the one keeping it busy attaching fails with
+    /// `EBUSY` after `timeout`.
+    fn replace_fd(&self, bf: RawFd, timeout: Duration) -> io::Result<()> {
+        let path = self.path().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "could not find the path of the loop device",
+            )
+        })?;
+        self.detach()?;
+
+        let start = Instant::now();
+        loop {
+            let result = if is_detached(&path)? {
+                loop_ioctl!(self.device, LOOP_SET_FD, bf as c_int).map(drop)
+            } else {
+                Err(io::Error::from_raw_os_error(libc::EBUSY))
+            };
+            match result {
+                Err(err)
+                    if err.raw_os_error() == Some(libc::EBUSY) && start.elapsed() < timeout =>
+                {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {
+                    return Err(io::Error::new(
+                        err.kind(),
+                        format!(
+                            "{}: the old backing file is still in use, it is only released once \
+                             every handle to the device is closed, including this one",
+                            self
+                        ),
+                    ))
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Get the status of the loop device.
+    ///
+    /// The kernel only reports the backing file name that was given when the device was attached
+    /// (truncated to 64 bytes), so the full path is read from `/sys/block/loopN/loop/backing_file`
+    /// where available.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// # ld.attach_file("disk.img").unwrap();
+    /// let info = ld.status().unwrap();
+    /// println!("{:?} at offset {}", info.backing_file, info.offset);
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the status of the device, ie `ENXIO` when the device is not attached.
+    pub fn status(&self) -> io::Result<LoopInfo> {
+        let mut info = LoopInfo::from(self.info()?);
+        if let Ok(mut backing_file) =
+            std::fs::read(platform::sysfs_device_dir(info.number).join("loop/backing_file"))
+        {
+            if backing_file.last() == Some(&b'\n') {
+                backing_file.pop();
+            }
+            info.backing_file = Some(PathBuf::from(OsString::from_vec(backing_file)));
+        }
+        Ok(info)
+    }
+
+    /// Check whether a backing file is attached to the loop device.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// if !ld.is_attached().unwrap() {
+    ///     ld.attach_file("disk.img").unwrap();
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the status of the device, other than the device not being attached.
+    pub fn is_attached(&self) -> io::Result<bool> {
+        match self.info() {
+            Ok(_) => Ok(true),
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Get the path of the backing file, `None` when the device is not attached.
+    ///
+    /// The path is read like in [`status`](LoopDevice::status). Without `/sys` only the name the
+    /// kernel keeps is available, which is truncated to 64 bytes for long paths. The path is
+    /// also only the one given when attaching, the file may have been moved or deleted since.
+    /// To tell for sure whether a device is backed by a given file, compare the
+    /// `backing_device` and `backing_inode` of the status to the metadata of the file as
+    /// [`find_by_backing_file`] does.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// match ld.backing_file().unwrap() {
+    ///     Some(path) => println!("{} is backed by {}", ld, path.display()),
+    ///     None => println!("{} is free", ld),
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the status of the device, other than the device not being attached.
+    pub fn backing_file(&self) -> io::Result<Option<PathBuf>> {
+        match self.status() {
+            Ok(info) => Ok(info.backing_file),
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Get the status, size and logical block size of the loop device in one go.
+    ///
+    /// This is meant for polling the state of a device, ie to display it, without calling each
+    /// of [`status`](LoopDevice::status), [`size`](LoopDevice::size) and
+    /// [`block_size`](LoopDevice::block_size) separately.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// # ld.attach_file("disk.img").unwrap();
+    /// let snapshot = ld.snapshot().unwrap();
+    /// println!("{:?}: {} bytes", snapshot.info.backing_file, snapshot.size);
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctls to get the status or size of the device, ie `ENXIO` when the device is not
+    /// attached.
+    pub fn snapshot(&self) -> io::Result<LoopSnapshot> {
+        Ok(LoopSnapshot {
+            info: self.status()?,
+            size: self.size()?,
+            block_size: self.block_size()?,
+        })
+    }
+
+    /// Get the offset in bytes from the start of the backing file the data starts at.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the status of the device, ie `ENXIO` when the device is not attached.
+    pub fn offset(&self) -> io::Result<u64> {
+        Ok(self.info()?.lo_offset)
+    }
+
+    /// Get the maximum size of the data in bytes, `0` means the whole backing file is used.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the status of the device, ie `ENXIO` when the device is not attached.
+    pub fn size_limit(&self) -> io::Result<u64> {
+        Ok(self.info()?.lo_sizelimit)
+    }
+
+    /// Get the paths of the partitions of the loop device, ordered by partition number.
+    ///
+    /// The partitions are read from `/sys/block/loopN` so they are only present after the
+    /// partition table was scanned, ie when attaching with
+    /// [`part_scan`](AttachOptions::part_scan) or after
+    /// [`reread_partitions`](LoopDevice::reread_partitions).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// ld.with().part_scan(true).attach("disk.img").unwrap();
+    /// for partition in ld.partitions().unwrap() {
+    ///     println!("{}", partition.display());
+    /// }
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the status of the device or when listing `/sys/block/loopN`.
+    pub fn partitions(&self) -> io::Result<Vec<PathBuf>> {
+        let number = self.info()?.lo_number;
+        let prefix = format!("loop{}p", number);
+        let mut partitions = Vec::new();
+        for entry in std::fs::read_dir(platform::sysfs_device_dir(number))? {
+            if let Some(partition) = entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix(&prefix))
+                .and_then(|partition| partition.parse::<u32>().ok())
+            {
+                partitions.push(partition);
+            }
+        }
+        partitions.sort_unstable();
+        Ok(partitions
+            .into_iter()
+            .map(|partition| platform::partition_path(number, partition))
+            .collect())
+    }
+
+    /// Wait until the device has at least `expected` partitions and their device nodes exist,
+    /// returning the paths of the partitions like [`partitions`](LoopDevice::partitions).
+    ///
+    /// With [`part_scan`](AttachOptions::part_scan) the kernel scans the partition table when
+    /// attaching, but the `loopNpM` nodes are created asynchronously by devtmpfs or udev. Call
+    /// this before using the partitions right after attaching.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// use std::time::Duration;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// ld.with().part_scan(true).attach("disk.img").unwrap();
+    /// let partitions = ld.wait_for_partitions(2, Duration::from_secs(5)).unwrap();
+    /// println!("{}", partitions[1].display());
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for the same reasons as
+    /// [`partitions`](LoopDevice::partitions). An error of kind
+    /// [`TimedOut`](io::ErrorKind::TimedOut) is returned if fewer than `expected` partitions
+    /// appeared within `timeout`.
+    pub fn wait_for_partitions(
+        &self,
+        expected: usize,
+        timeout: Duration,
+    ) -> io::Result<Vec<PathBuf>> {
+        let start = Instant::now();
+        loop {
+            let partitions = self.partitions()?;
+            if partitions.len() >= expected && partitions.iter().all(|p| p.exists()) {
+                return Ok(partitions);
+            }
+            if start.elapsed() >= timeout {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "timed out waiting for {} partitions of {}, found {}",
+                        expected,
+                        self,
+                        partitions.len()
+                    ),
+                ));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Get the path of the loop device.
+    ///
+    /// The path is read from `/proc/self/fd`. When `/proc` is not available (ie in minimal
+    /// containers or chroots) the path is instead built from the number of the device, found
+    /// in `/sys/dev/block` or from the status of an attached device. `None` is returned if
+    /// the file is not a loop device or its number can not be found.
+    pub fn path(&self) -> Option<PathBuf> {
+        let mut p = PathBuf::from("/proc/self/fd");
+        p.push(self.device.as_raw_fd().to_string());
+        std::fs::read_link(&p)
+            .ok()
+            .or_else(|| self.number_from_rdev().map(platform::device_path))
+    }
+
+    /// The number of the loop device from its device number, without relying on `/proc`.
+    #[allow(clippy::unnecessary_cast)]
+    fn number_from_rdev(&self) -> Option<u32> {
+        let rdev = self.device.metadata().ok()?.rdev();
+        let (major, minor) = unsafe { (libc::major(rdev) as u32, libc::minor(rdev) as u32) };
+        if major != LOOP_MAJOR {
+            return None;
+        }
+        // The minor is only the number of the device when the loop module has `max_part = 0`,
+        // sysfs knows the name of the device whatever the minor is.
+        let sysfs_number = std::fs::read_link(format!("/sys/dev/block/{}:{}", major, minor))
+            .ok()
+            .and_then(|link| {
+                link.file_name()?
+                    .to_str()?
+                    .strip_prefix("loop")?
+                    .parse()
+                    .ok()
+            });
+        sysfs_number.or_else(|| {
+            let number = self.info().ok()?.lo_number;
+            is_whole_device_minor(number, minor).then_some(number)
+        })
+    }
+
+    /// Get the number of the loop device as the kernel knows it, ie `3` for `/dev/loop3`.
+    ///
+    /// The number in [`path`](LoopDevice::path) comes from the name of the device node that was
+    /// opened, which may not match the device if the node was renamed or created by hand. In
+    /// debug builds this asserts that the two agree when the path is that of a loop device.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop3").unwrap();
+    /// # ld.attach_file("disk.img").unwrap();
+    /// assert_eq!(ld.number().unwrap(), 3);
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the status of the device, ie `ENXIO` if it is not attached.
+    pub fn number(&self) -> io::Result<u32> {
+        let number = self.info()?.lo_number;
+        let path = self.path();
+        debug_assert!(
+            path.as_deref()
+                .and_then(platform::device_number)
+                .is_none_or(|path_number| path_number == number),
+            "{:?} is the node of loop device {}",
+            path,
+            number
+        );
+        Ok(number)
+    }
+
+    /// Get the device major number
+    ///
+    /// # Errors
+    ///
+    /// This function needs to stat the backing file and can fail if there is
+    /// an IO error.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn major(&self) -> io::Result<u32> {
+        self.device
+            .metadata()
+            .map(|m| unsafe { libc::major(m.rdev()) })
+            .map(|m| m as u32)
+    }
+
+    /// Get the device major number
+    ///
+    /// # Errors
+    ///
+    /// This function needs to stat the backing file and can fail if there is
+    /// an IO error.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn minor(&self) -> io::Result<u32> {
+        self.device
+            .metadata()
+            .map(|m| unsafe { libc::minor(m.rdev()) })
+            .map(|m| m as u32)
+    }
+
+    /// Detach a loop device from its backing file.
+    ///
+    /// Note that the device won't fully detach until a short delay after the underling device file
+    /// gets closed. This happens when `LoopDev` goes out of scope so you should ensure the `LoopDev`
+    /// lives for a short a time as possible.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// # ld.attach_file("disk.img").unwrap();
+    /// ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to detach the backing file from the device. When the device is busy
+    /// the error message lists the holders of the device and its partitions
+    /// found in sysfs.
+    pub fn detach(&self) -> io::Result<()> {
+        loop_ioctl!(self.device, LOOP_CLR_FD).map_err(|err| {
+            if err.raw_os_error() != Some(libc::EBUSY) {
+                return err;
+            }
+            let holders = self.sysfs_holders().unwrap_or_default();
+            let message = if holders.is_empty() {
+                format!(
+                    "{}: the device may have mounted partitions or open references",
+                    err
+                )
+            } else {
+                format!("{}: the device is held by {}", err, holders.join(", "))
+            };
+            io::Error::new(err.kind(), message)
+        })?;
+        Ok(())
+    }
+
+    /// Detach the device if it is attached, doing nothing if it is not. Unlike
+    /// [`detach`](LoopDevice::detach) this never fails because the device is already detached,
+    /// ie by autoclear or a previous cleanup, so teardown code can safely run more than once.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// ld.ensure_detached().unwrap();
+    /// ld.ensure_detached().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for the same reasons as
+    /// [`is_attached`](LoopDevice::is_attached) and [`detach`](LoopDevice::detach), other than
+    /// the device not being attached.
+    pub fn ensure_detached(&self) -> io::Result<()> {
+        if !self.is_attached()? {
+            return Ok(());
+        }
+        match self.detach() {
+            // Detached by someone else since it was checked.
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// The names of the devices holding this device, ie `dm-0` for a device mapper target built
+    /// on top of it, as listed in `/sys/block/loopN/holders`. Holders keep the device busy so
+    /// [`detach`](LoopDevice::detach) fails until they are removed.
+    ///
+    /// Only holders of the whole device are listed, not those of its partitions.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop3").unwrap();
+    /// # ld.attach_file("disk.img").unwrap();
+    /// for holder in ld.holders().unwrap() {
+    ///     println!("loop3 is held by {}, remove it first", holder);
+    /// }
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the status of the device or when listing `/sys/block/loopN/holders`.
+    pub fn holders(&self) -> io::Result<Vec<String>> {
+        let number = self.info()?.lo_number;
+        dir_entry_names(&platform::sysfs_device_dir(number).join("holders"))
+    }
+
+    /// The names of the devices this device is built on as listed in `/sys/block/loopN/slaves`.
+    /// The backing file of a loop device is not a slave, so this is usually empty.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the status of the device or when listing `/sys/block/loopN/slaves`.
+    pub fn slaves(&self) -> io::Result<Vec<String>> {
+        let number = self.info()?.lo_number;
+        dir_entry_names(&platform::sysfs_device_dir(number).join("slaves"))
+    }
+
+    /// The names of the holders of the device and its partitions listed in
+    /// `/sys/block/loopN/holders` and `/sys/block/loopN/loopNpM/holders`.
+    fn sysfs_holders(&self) -> io::Result<Vec<String>> {
+        let number = self.info()?.lo_number;
+        let device_dir = platform::sysfs_device_dir(number);
+        let mut holder_dirs = vec![device_dir.join("holders")];
+        for partition in self.partitions()? {
+            if let Some(name) = partition.file_name() {
+                holder_dirs.push(device_dir.join(name).join("holders"));
+            }
+        }
+
+        let mut holders = Vec::new();
+        for holder_dir in holder_dirs {
+            holders.extend(dir_entry_names(&holder_dir)?);
+        }
+        Ok(holders)
+    }
+
+    /// Whether the device or one of its partitions is mounted according to
+    /// `/proc/self/mountinfo`.
+    fn is_mounted(&self) -> io::Result<bool> {
+        let number = self.info()?.lo_number;
+        let device_dir = platform::sysfs_device_dir(number);
+        let mut dev_files = vec![device_dir.join("dev")];
+        for partition in self.partitions()? {
+            if let Some(name) = partition.file_name() {
+                dev_files.push(device_dir.join(name).join("dev"));
+            }
+        }
+
+        let mut devices = Vec::new();
+        for dev_file in dev_files {
+            devices.push(std::fs::read_to_string(dev_file)?.trim().to_owned());
+        }
+        Ok(std::fs::read_to_string("/proc/self/mountinfo")?
+            .lines()
+            .filter_map(|line| line.split(' ').nth(2))
+            .any(|device| devices.iter().any(|d| d == device)))
+    }
+
+    /// Detach a loop device from its backing file and wait until it is fully detached.
+    ///
+    /// Unlike [`detach`](LoopDevice::detach) this consumes the `LoopDevice` so that the device
+    /// file can be closed, then polls the device until the kernel reports that it has no backing
+    /// file. Once this returns the device can be reused.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// use std::time::Duration;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// # ld.attach_file("disk.img").unwrap();
+    /// ld.detach_and_wait(Duration::from_secs(1)).unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctls to detach the backing file from the device or to get the status of the device.
+    /// An error of kind [`TimedOut`](io::ErrorKind::TimedOut) is returned if the device is
+    /// still attached after `timeout`.
+    pub fn detach_and_wait(self, timeout: Duration) -> io::Result<()> {
+        let path = self.path().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "could not find the path of the loop device",
+            )
+        })?;
+        self.detach()?;
+        drop(self);
+
+        let start = Instant::now();
+        while !is_detached(&path)? {
+            if start.elapsed() >= timeout {
+                return Err(detach_timed_out());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    /// Detach a loop device from its backing file and wait for the kernel to announce that it is
+    /// fully detached.
+    ///
+    /// Like [`detach_and_wait`](LoopDevice::detach_and_wait) this consumes the `LoopDevice` so
+    /// that the device file can be closed. Instead of polling the status every few milliseconds
+    /// it sleeps until the kernel sends a uevent for the device on a `NETLINK_KOBJECT_UEVENT`
+    /// socket. The status is still checked every 100ms in case the uevents are not delivered,
+    /// ie in a network namespace. If the socket can not be opened this falls back to
+    /// [`detach_and_wait`](LoopDevice::detach_and_wait).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// use std::time::Duration;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// # ld.attach_file("disk.img").unwrap();
+    /// ld.detach_and_wait_uevent(Duration::from_secs(1)).unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for the same reasons as
+    /// [`detach_and_wait`](LoopDevice::detach_and_wait), or when receiving from the uevent
+    /// socket fails.
+    pub fn detach_and_wait_uevent(self, timeout: Duration) -> io::Result<()> {
+        let socket = match UeventSocket::open() {
+            Ok(socket) => socket,
+            Err(err) => {
+                debug!(
+                    "falling back to polling, cannot open a uevent socket: {}",
+                    err
+                );
+                return self.detach_and_wait(timeout);
+            }
+        };
+        let number = self.info()?.lo_number;
+        let path = self.path().unwrap_or_else(|| platform::device_path(number));
+        // Subscribe before detaching so the uevent can not be missed.
+        self.detach()?;
+        drop(self);
+
+        let devpath_suffix = format!("/loop{}", number);
+        let start = Instant::now();
+        while !is_detached(&path)? {
+            let remaining = timeout
+                .checked_sub(start.elapsed())
+                .ok_or_else(detach_timed_out)?;
+            socket.wait_for(&devpath_suffix, remaining.min(Duration::from_millis(100)))?;
+        }
+        Ok(())
+    }
+
+    /// Resize a live loop device. If the size of the backing file changes this can be called to
+    /// inform the loop driver about the new size.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to set the capacity of the device.
+    pub fn set_capacity(&self) -> io::Result<()> {
+        loop_ioctl!(self.device, LOOP_SET_CAPACITY)?;
+        Ok(())
+    }
+
+    /// Resize a live loop device and check it picked up the expected size. Returns the size of
+    /// the device in bytes after the resize.
+    ///
+    /// This catches the backing file not actually having grown or a size limit capping the
+    /// device below the expected size.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// // ... grow the backing file to 1 GiB ...
+    /// ld.set_capacity_checked(1024 * 1024 * 1024).unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the ioctls to set
+    /// the capacity and get the size of the device, or an error of kind `Other` if the size of
+    /// the device does not match `expected`.
+    pub fn set_capacity_checked(&self, expected: u64) -> io::Result<u64> {
+        self.set_capacity()?;
+        let size = self.size()?;
+        if size != expected {
+            return Err(io::Error::other(format!(
+                "loop device size is {} bytes after resizing, expected {}",
+                size, expected
+            )));
+        }
+        Ok(size)
+    }
+
+    /// Get the size of the device in bytes.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the size of the device.
+    pub fn size(&self) -> io::Result<u64> {
+        let mut size: u64 = 0;
+        loop_ioctl!(self.device, BLKGETSIZE64, &mut size)?;
+        Ok(size)
+    }
+
+    /// Check whether the device is read only at the block layer.
+    ///
+    /// This is the effective state of the block device rather than the
+    /// [`read_only`](AttachOptions::read_only) flag that was asked for, ie a device is also read
+    /// only when its backing file could only be opened read only.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// # ld.attach_file("disk.img").unwrap();
+    /// if ld.is_read_only().unwrap() {
+    ///     println!("{} is read only", ld);
+    /// }
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the read only state of the device.
+    pub fn is_read_only(&self) -> io::Result<bool> {
+        let mut read_only: c_int = 0;
+        loop_ioctl!(self.device, BLKROGET, &mut read_only)?;
+        Ok(read_only != 0)
+    }
+
+    /// Check whether the autoclear flag is set, ie the device is detached once the last handle
+    /// to it is closed. See [`set_autoclear`](LoopDevice::set_autoclear).
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the status of the device.
+    pub fn is_autoclear(&self) -> io::Result<bool> {
+        Ok(self.info()?.lo_flags & LO_FLAGS_AUTOCLEAR != 0)
+    }
+
+    /// Check whether the kernel scans the partition table of the device, as set with
+    /// [`part_scan`](AttachOptions::part_scan).
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the status of the device.
+    pub fn is_part_scan(&self) -> io::Result<bool> {
+        Ok(self.info()?.lo_flags & LO_FLAGS_PARTSCAN != 0)
+    }
+
+    /// Get the logical block size of the device in bytes, the smallest unit it can address.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the logical block size of the device.
+    pub fn block_size(&self) -> io::Result<u32> {
+        let mut block_size: c_int = 0;
+        loop_ioctl!(self.device, BLKSSZGET, &mut block_size)?;
+        Ok(block_size as u32)
+    }
+
+    /// Get the physical block size of the device in bytes as reported by the kernel. Filesystems
+    /// should be aligned to this for best performance.
+    ///
+    /// This can not be set directly, see [`set_block_size`](LoopDevice::set_block_size).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// # ld.attach_file("disk.img").unwrap();
+    /// println!(
+    ///     "logical: {}, physical: {}",
+    ///     ld.block_size().unwrap(),
+    ///     ld.physical_block_size().unwrap()
+    /// );
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the physical block size of the device.
+    pub fn physical_block_size(&self) -> io::Result<u32> {
+        let mut block_size: libc::c_uint = 0;
+        loop_ioctl!(self.device, BLKPBSZGET, &mut block_size)?;
+        Ok(block_size)
+    }
+
+    /// Get the read-ahead of the device in KiB, as in `/sys/block/loopN/queue/read_ahead_kb`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// println!("read-ahead: {} KiB", ld.read_ahead_kb().unwrap());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the read-ahead of the device.
+    pub fn read_ahead_kb(&self) -> io::Result<u32> {
+        let mut sectors: libc::c_long = 0;
+        loop_ioctl!(self.device, BLKRAGET, &mut sectors)?;
+        Ok((sectors / 2) as u32)
+    }
+
+    /// Set the read-ahead of the device in KiB. Raising it speeds up sequential reads of large
+    /// images. The kernel rounds it down to a multiple of the page size.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// ld.set_read_ahead_kb(4096).unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to set the read-ahead of the device, ie `EACCES` without `CAP_SYS_ADMIN`.
+    /// An error of kind [`InvalidInput`](io::ErrorKind::InvalidInput) is returned if `kb` does
+    /// not fit in the argument of the ioctl, which can happen on 32-bit targets.
+    pub fn set_read_ahead_kb(&self, kb: u32) -> io::Result<()> {
+        let sectors = libc::c_ulong::from(kb).checked_mul(2).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the read-ahead of {} KiB is too large", kb),
+            )
+        })?;
+        loop_ioctl!(self.device, BLKRASET, sectors)?;
+        Ok(())
+    }
+
+    /// Check whether the device accepts discard (TRIM) requests.
+    ///
+    /// The loop driver passes discards on by punching holes into the backing file, which frees
+    /// the space on the filesystem it is on. This is only supported when that filesystem
+    /// supports hole punching (`fallocate` with `FALLOC_FL_PUNCH_HOLE`), as ext4, xfs, btrfs and
+    /// tmpfs do. Attach the device writable to discard, ie by `fstrim` or `mkfs`, as discarding
+    /// on a read only device fails.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// # ld.attach_file("disk.img").unwrap();
+    /// if ld.discard_supported().unwrap() {
+    ///     println!("discarded blocks are freed in the backing file");
+    /// }
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the status of the device or when reading
+    /// `/sys/block/loopN/queue/discard_max_bytes`.
+    pub fn discard_supported(&self) -> io::Result<bool> {
+        let number = self.info()?.lo_number;
+        let max_bytes = std::fs::read_to_string(
+            platform::sysfs_device_dir(number).join("queue/discard_max_bytes"),
+        )?;
+        let max_bytes = max_bytes.trim().parse::<u64>().map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid discard_max_bytes: {}", err),
+            )
+        })?;
+        Ok(max_bytes > 0)
+    }
+
+    /// Get the size, logical and physical block size of the device in one go.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// # ld.attach_file("disk.img").unwrap();
+    /// let geometry = ld.geometry().unwrap();
+    /// println!(
+    ///     "{} sectors of {} bytes",
+    ///     geometry.sectors(),
+    ///     geometry.logical_block_size
+    /// );
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctls to get the size or block sizes of the device.
+    pub fn geometry(&self) -> io::Result<Geometry> {
+        Ok(Geometry {
+            size_bytes: self.size()?,
+            logical_block_size: self.block_size()?,
+            physical_block_size: self.physical_block_size()?,
+        })
+    }
+
+    /// Set the logical block size of the device in bytes. This must be a power of two between
+    /// 512 and the page size of the system.
+    ///
+    /// The loop driver only lets the logical block size be set, the physical block size follows
+    /// it. A 4Kn disk (4096 byte logical and physical blocks) can be emulated this way, a 512e
+    /// disk (512 byte logical, 4096 byte physical blocks) can not. See
+    /// [`physical_block_size`](LoopDevice::physical_block_size) for what the kernel reports.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to set the block size of the device.
+    pub fn set_block_size(&self, block_size: u32) -> io::Result<()> {
+        loop_ioctl!(
+            self.device,
+            LOOP_SET_BLOCK_SIZE,
+            block_size as libc::c_ulong
+        )?;
+        Ok(())
+    }
+
+    /// Re-read the partition table of the device. If the backing file changed (ie after a
+    /// [`set_capacity`](LoopDevice::set_capacity)) this updates the partition device nodes.
+    ///
+    /// The device must be idle for this to succeed, if any of its partitions are in use (ie
+    /// mounted) the kernel refuses to re-read the table and `EBUSY` is returned.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to re-read the partition table of the device.
+    pub fn reread_partitions(&self) -> io::Result<()> {
+        loop_ioctl!(self.device, BLKRRPART)?;
+        Ok(())
+    }
+
+    /// Enable or disable the autoclear flag on an attached device. With autoclear set the device
+    /// is detached automatically once the last reference to it is closed.
+    ///
+    /// The current status of the device is read back and only the autoclear flag is changed, so
+    /// the offset, size limit and all other flags are preserved.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// ld.attach_file("disk.img").unwrap();
+    /// ld.set_autoclear(true).unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctls to get or set the status of the device.
+    pub fn set_autoclear(&self, enable: bool) -> io::Result<()> {
+        let mut info = self.info()?;
+        if enable {
+            info.lo_flags |= LO_FLAGS_AUTOCLEAR;
+        } else {
+            info.lo_flags &= !LO_FLAGS_AUTOCLEAR;
+        }
+        self.set_info(&info)
+    }
+
+    /// Change the maximum size of the data in bytes on an attached device, `0` uses the whole
+    /// backing file.
+    ///
+    /// The current status of the device is read back and only the size limit is changed, so the
+    /// offset and all flags are preserved. There is deliberately no way to change the offset of
+    /// a live device, it would shift the data under anyone using the device, so only the size
+    /// limit and flags should be changed after attaching.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// ld.attach_file("disk.img").unwrap();
+    /// ld.set_size_limit(1024 * 1024).unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctls to get or set the status of the device.
+    pub fn set_size_limit(&self, size_limit: u64) -> io::Result<()> {
+        let mut info = self.info()?;
+        info.lo_sizelimit = size_limit;
+        self.set_info(&info)
+    }
+
+    /// Refuse to change the offset of the device.
+    ///
+    /// The offset of a loop device is fixed once it is attached, changing it would shift the
+    /// data under anyone using the device. Rather than leaving users to wonder about an `EINVAL`
+    /// from the kernel this always returns an error of kind
+    /// [`Unsupported`](io::ErrorKind::Unsupported) wrapping
+    /// [`Error::OffsetChangeUnsupported`](crate::Error::OffsetChangeUnsupported). Set the offset
+    /// with [`AttachOptions::offset`] when attaching instead.
+    ///
+    /// # Errors
+    ///
+    /// This function always returns an error, or an error for various reasons when calling the
+    /// ioctl to get the status of the device.
+    pub fn try_set_offset(&self, _offset: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            crate::Error::OffsetChangeUnsupported {
+                attached: self.is_attached()?,
+            },
+        ))
+    }
+
+    /// Get the current `loop_info64` of the device.
+    fn info(&self) -> io::Result<loop_info64> {
+        let mut info = loop_info64::default();
+        loop_ioctl!(self.device, LOOP_GET_STATUS64, &mut info)?;
+        Ok(info)
+    }
+
+    /// Set the `loop_info64` of the device.
+    fn set_info(&self, info: &loop_info64) -> io::Result<()> {
+        loop_ioctl!(self.device, LOOP_SET_STATUS64, info)?;
+        Ok(())
+    }
+
+    /// Enable or disable direct I/O for the backing file.
+    ///
+    /// Direct I/O bypasses the page cache of the backing file. It requires the filesystem of the
+    /// backing file to support `O_DIRECT`, the offset to be a multiple of the logical block size
+    /// of the backing filesystem and the logical block size of the loop device to be at least
+    /// that block size.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to set the direct io flag for the device. An error of kind
+    /// [`InvalidInput`](io::ErrorKind::InvalidInput) is returned when the backing
+    /// file does not meet the requirements for direct I/O.
+    #[cfg(feature = "direct_io")]
+    pub fn set_direct_io(&self, direct_io: bool) -> io::Result<()> {
+        loop_ioctl!(
+            self.device,
+            LOOP_SET_DIRECT_IO,
+            if direct_io { 1 } else { 0 }
+        )
+        .map_err(|err| {
+            if direct_io && err.raw_os_error() == Some(libc::EINVAL) {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{}: the backing file does not support direct I/O or is not aligned",
+                        err
+                    ),
+                )
+            } else {
+                err
+            }
+        })?;
+        Ok(())
+    }
+
+    /// Check whether direct I/O is enabled for the backing file.
+    ///
+    /// Enabling direct I/O with [`set_direct_io`](LoopDevice::set_direct_io) can fail silently
+    /// when the backing file does not meet the alignment requirements, so this can be used to
+    /// verify it took effect.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to get the status of the device.
+    #[cfg(feature = "direct_io")]
+    pub fn direct_io(&self) -> io::Result<bool> {
+        Ok(self.info()?.lo_flags & LO_FLAGS_DIRECT_IO != 0)
+    }
+}
+
+impl From<loop_info64> for LoopInfo {
+    fn from(info: loop_info64) -> Self {
+        Self {
+            number: info.lo_number,
+            backing_file: Some(PathBuf::from(OsStr::from_bytes(until_nul(
+                &info.lo_file_name,
+            ))))
+            .filter(|path| !path.as_os_str().is_empty()),
+            backing_device: info.lo_device,
+            backing_inode: info.lo_inode,
+            backing_rdevice: info.lo_rdevice,
+            offset: info.lo_offset,
+            size_limit: info.lo_sizelimit,
+            read_only: info.lo_flags & LO_FLAGS_READ_ONLY != 0,
+            autoclear: info.lo_flags & LO_FLAGS_AUTOCLEAR != 0,
+            part_scan: info.lo_flags & LO_FLAGS_PARTSCAN != 0,
+            direct_io: info.lo_flags & LO_FLAGS_DIRECT_IO != 0,
+            encrypt_type: info.lo_encrypt_type,
+            crypt_name: Some(String::from_utf8_lossy(until_nul(&info.lo_crypt_name)).into_owned())
+                .filter(|name| !name.is_empty()),
+            init: info.lo_init,
+        }
+    }
+}
+
+impl From<&LoopInfo> for loop_info64 {
+    fn from(info: &LoopInfo) -> Self {
+        let mut raw = Self {
+            lo_number: info.number,
+            lo_device: info.backing_device,
+            lo_inode: info.backing_inode,
+            lo_rdevice: info.backing_rdevice,
+            lo_offset: info.offset,
+            lo_sizelimit: info.size_limit,
+            lo_encrypt_type: info.encrypt_type,
+            lo_init: info.init,
+            ..Default::default()
+        };
+        for (flag, set) in [
+            (LO_FLAGS_READ_ONLY, info.read_only),
+            (LO_FLAGS_AUTOCLEAR, info.autoclear),
+            (LO_FLAGS_PARTSCAN, info.part_scan),
+            (LO_FLAGS_DIRECT_IO, info.direct_io),
+        ] {
+            if set {
+                raw.lo_flags |= flag;
+            }
+        }
+        if let Some(backing_file) = &info.backing_file {
+            copy_truncated(&mut raw.lo_file_name, backing_file.as_os_str().as_bytes());
+        }
+        if let Some(crypt_name) = &info.crypt_name {
+            copy_truncated(&mut raw.lo_crypt_name, crypt_name.as_bytes());
+        }
+        raw
+    }
+}
+
+/// An attach that failed and then also failed to detach the half configured device again.
+///
+/// The kind of the returned `io::Error` is that of the attach failure, the detach failure is
+/// available as the [`source`](Error::source) of this error.
+#[derive(Debug)]
+struct CleanupError {
+    error: io::Error,
+    cleanup: io::Error,
+}
+
+impl fmt::Display for CleanupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (detaching the device again also failed: {})",
+            self.error, self.cleanup
+        )
+    }
+}
+
+impl Error for CleanupError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.cleanup)
+    }
+}
+
+/// Used to set options when attaching a device. Created with [`LoopDevice::with`()].
+///
+/// # Examples
+///
+/// Enable partition scanning on attach:
+///
+/// ```no_run
+/// use loopdev::LoopDevice;
+/// let mut ld = LoopDevice::open("/dev/loop0").unwrap();
+/// ld.with()
+///     .part_scan(true)
+///     .attach("disk.img")
+///     .unwrap();
+/// # ld.detach().unwrap();
+/// ```
+///
+/// A 1MiB slice of the file located at 1KiB into the file.
+///
+/// ```no_run
+/// use loopdev::LoopDevice;
+/// let mut ld = LoopDevice::open("/dev/loop0").unwrap();
+/// ld.with()
+///     .offset(1024*1024)
+///     .size_limit(1024*1024*1024)
+///     .attach("disk.img")
+///     .unwrap();
+/// # ld.detach().unwrap();
+/// ```
+#[must_use]
+pub struct AttachOptions<'d> {
+    device: &'d LoopDevice,
+    config: AttachConfig,
+}
+
+impl AttachOptions<'_> {
+    /// Replace all options with the given status. This gives access to every field the kernel
+    /// accepts when attaching, the fields the kernel reports as read only (ie `number`) are
+    /// ignored by it. The `backing_file` of `info` is not used either, the kernel records the
+    /// file that is attached instead. Any option set after this is applied on top of `info`.
+    ///
+    /// # Examples
+    ///
+    /// Attach with the same options as another device.
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let info = LoopDevice::open("/dev/loop0").unwrap().status().unwrap();
+    /// let ld = LoopDevice::open("/dev/loop1").unwrap();
+    /// ld.with().with_info(info).attach("disk.img").unwrap();
+    /// # ld.detach().unwrap();
+    /// ```
+    pub fn with_info(mut self, info: LoopInfo) -> Self {
+        self.config = self.config.with_info(info);
+        self
+    }
+
+    /// Replace all options with the given config. Any option set after this is applied on top of
+    /// `config`.
+    pub fn with_config(mut self, config: AttachConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The options that will be used to attach the device.
+    pub fn config(&self) -> &AttachConfig {
+        &self.config
+    }
+
+    /// Offset in bytes from the start of the backing file the data will start at.
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.config = self.config.offset(offset);
+        self
+    }
+
+    /// Maximum size of the data in bytes.
+    pub fn size_limit(mut self, size_limit: u64) -> Self {
+        self.config = self.config.size_limit(size_limit);
+        self
+    }
+
+    /// Offset in sectors from the start of the backing file the data will start at, ie the LBA
+    /// of a partition in a disk image. A sector is the [`block_size`](AttachOptions::block_size)
+    /// set so far, or 512 bytes, so set the block size first.
+    ///
+    /// # Examples
+    ///
+    /// Attach the partition starting at LBA 2048 that is 204800 sectors long.
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// ld.with()
+    ///     .offset_sectors(2048)
+    ///     .size_limit_sectors(204800)
+    ///     .attach("disk.img")
+    ///     .unwrap();
+    /// # ld.detach().unwrap();
+    /// ```
+    pub fn offset_sectors(mut self, lba: u64) -> Self {
+        self.config = self.config.offset_sectors(lba);
+        self
+    }
+
+    /// Maximum size of the data in sectors. A sector is the
+    /// [`block_size`](AttachOptions::block_size) set so far, or 512 bytes.
+    pub fn size_limit_sectors(mut self, count: u64) -> Self {
+        self.config = self.config.size_limit_sectors(count);
+        self
+    }
+
+    /// Set read only flag. The backing file is then also opened read only.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.config = self.config.read_only(read_only);
+        self
+    }
+
+    /// Set autoclear flag
+    pub fn autoclear(mut self, autoclear: bool) -> Self {
+        self.config = self.config.autoclear(autoclear);
+        self
+    }
+
+    /// Enable or disable direct I/O for the backing file. When attaching by path the backing
+    /// file is opened with `O_DIRECT`, see [`LoopDevice::set_direct_io`] for the requirements.
+    /// A file system that rejects `O_DIRECT` gets the file opened buffered, enabling direct I/O
+    /// then fails with an error of kind [`InvalidInput`](io::ErrorKind::InvalidInput) and the
+    /// device is detached again.
+    #[cfg(feature = "direct_io")]
+    pub fn set_direct_io(mut self, direct_io: bool) -> Self {
+        self.config = self.config.set_direct_io(direct_io);
+        self
+    }
+
+    /// Logical block size of the device in bytes. This must be a power of two between 512 and the
+    /// page size of the system. The block size is set before the partition table is scanned so
+    /// it is honored by [`part_scan`](AttachOptions::part_scan). On kernels supporting
+    /// `LOOP_CONFIGURE` (see [`LoopControl::supports_configure`]) it is set atomically with the
+    /// backing file, otherwise right after attaching.
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.config = self.config.block_size(block_size);
+        self
+    }
+
+    /// Force the kernel to scan the partition table on a newly created loop device. Note that the
+    /// partition table parsing depends on sector sizes. The default is sector size is 512 bytes,
+    /// use [`block_size`](AttachOptions::block_size) to change it.
+    pub fn part_scan(mut self, enable: bool) -> Self {
+        self.config = self.config.part_scan(enable);
+        self
+    }
+
+    /// Replace all flags at once, the individual flag options set before are overridden.
+    ///
+    /// # Examples
+    ///
+    /// Attach with the same flags as another device.
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let flags = LoopDevice::open("/dev/loop0").unwrap().status().unwrap().flags();
+    /// let ld = LoopDevice::open("/dev/loop1").unwrap();
+    /// ld.with().flags(flags).attach("disk.img").unwrap();
+    /// # ld.detach().unwrap();
+    /// ```
+    pub fn flags(mut self, flags: LoopFlags) -> Self {
+        self.config = self.config.flags(flags);
+        self
+    }
+
+    /// Take an exclusive advisory lock (`flock`) on the backing file for as long as the device
+    /// is attached. Attaching fails with [`ResourceBusy`](io::ErrorKind::ResourceBusy) if another
+    /// device or process already holds a lock on the file, which prevents attaching the same
+    /// file twice by accident. Only users that also lock the file are kept out.
+    pub fn exclusive(mut self, exclusive: bool) -> Self {
+        self.config = self.config.exclusive(exclusive);
+        self
+    }
+
+    /// Set the size limit to the current length of the backing file after the offset, overriding
+    /// [`size_limit`](AttachOptions::size_limit). This freezes the size of the device when
+    /// attaching a file that keeps growing, ie a sparse image attached with
+    /// [`attach_fd`](AttachOptions::attach_fd). A file that is no longer than the offset leaves
+    /// the size unlimited.
+    pub fn size_limit_from_file(mut self, enable: bool) -> Self {
+        self.config = self.config.size_limit_from_file(enable);
+        self
+    }
+
+    /// Replace the backing file if the device is already attached. The device is detached first
+    /// and attaching is retried once it is free, which reclaims a device set up by an earlier
+    /// run. By default attaching to a device that is in use fails with `EBUSY`.
+    ///
+    /// The old backing file is only released once nothing else has the device open, ie a
+    /// mounted filesystem or another `LoopDevice` handle keeps it busy and attaching then fails
+    /// with `EBUSY` after a short wait. Recent kernels also wait for the handle that is being
+    /// attached through to be closed, replacing then always fails this way. Detach with
+    /// [`detach_and_wait`](LoopDevice::detach_and_wait) and open the device again instead.
+    pub fn replace(mut self, replace: bool) -> Self {
+        self.config = self.config.replace(replace);
+        self
+    }
+
+    /// Create the backing file as a sparse file of `size` bytes if it does not exist yet, so a
+    /// path can be attached in one step whether or not the image was provisioned before. An
+    /// existing file or block device is attached as it is, without changing its size. A file
+    /// created this way is removed again if attaching fails.
+    ///
+    /// This only applies when attaching by path. Attaching fails with
+    /// [`InvalidInput`](io::ErrorKind::InvalidInput) if the path exists but is neither a file
+    /// nor a block device.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// ld.with()
+    ///     .create_backing(64 * 1024 * 1024)
+    ///     .attach("scratch.img")
+    ///     .unwrap();
+    /// # ld.detach().unwrap();
+    /// ```
+    pub fn create_backing(mut self, size: u64) -> Self {
+        self.config = self.config.create_backing(size);
+        self
+    }
+
+    /// Attach the loop device to a file with the set options.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons. Either when
+    /// opening the backing file (see
+    /// [`OpenOptions::open`](https://doc.rust-lang.org/std/fs/struct.OpenOptions.html)
+    /// for further details) or when calling the ioctl to attach the backing
+    /// file to the device. If the device can not be detached again after a
+    /// failed attach, the detach error is the [`source`](Error::source) of the
+    /// inner error.
+    ///
+    /// Options that can never work are rejected with an error of kind
+    /// [`InvalidInput`](io::ErrorKind::InvalidInput) before the device is touched: a block size
+    /// that is not a power of two between 512 and the page size, direct I/O with an offset
+    /// that is not a multiple of 512 bytes, and an offset plus size limit that does not fit in a
+    /// file offset. Read only devices may use direct I/O.
+    pub fn attach(self, backing_file: impl AsRef<Path>) -> io::Result<()> {
+        self.device.attach_with_config(backing_file, &self.config)
+    }
+
+    /// Attach the loop device to a file with the set options and return an owned handle to the
+    /// attached device.
+    ///
+    /// The returned `LoopDevice` has its own copy of the device file descriptor so it outlives
+    /// the device the options were created from, which allows attaching in a single expression.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let ld = LoopControl::open()
+    ///     .unwrap()
+    ///     .next_free()
+    ///     .unwrap()
+    ///     .with()
+    ///     .offset(1024 * 1024)
+    ///     .into_attached("disk.img")
+    ///     .unwrap();
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for the same reasons as
+    /// [`attach`](AttachOptions::attach) or when duplicating the device file descriptor fails.
+    pub fn into_attached(self, backing_file: impl AsRef<Path>) -> io::Result<LoopDevice> {
+        let device = self.device;
+        self.attach(backing_file)?;
+        device.clone_attached()
+    }
+
+    /// Attach the loop device to a file with the set options and return an owned handle to the
+    /// attached device together with the opened backing file.
+    ///
+    /// Like [`into_attached`](AttachOptions::into_attached) but the backing file is kept open,
+    /// ie to read or write it directly, to hold an advisory lock taken with
+    /// [`exclusive`](AttachOptions::exclusive) from this process, or to check how it was opened.
+    /// The file is opened read only if it could not be opened for writing.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let (ld, file) = LoopControl::open()
+    ///     .unwrap()
+    ///     .next_free()
+    ///     .unwrap()
+    ///     .with()
+    ///     .autoclear(true)
+    ///     .attach_owned("disk.img")
+    ///     .unwrap();
+    /// println!("{} is backed by {} bytes", ld, file.metadata().unwrap().len());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for the same reasons as
+    /// [`attach`](AttachOptions::attach) or when duplicating the device file descriptor fails.
+    pub fn attach_owned(self, backing_file: impl AsRef<Path>) -> io::Result<(LoopDevice, File)> {
+        let file = self
+            .device
+            .attach_path(backing_file.as_ref(), &self.config)?;
+        Ok((self.device.clone_attached()?, file))
+    }
+
+    /// Create an anonymous in-memory file of `size` bytes with `memfd_create` and attach the loop
+    /// device to it with the set options.
+    ///
+    /// The device stays valid for as long as the kernel holds the backing file, but the returned
+    /// `File` is the only handle to the memory outside of the device. Keep it around to read or
+    /// write the backing data directly or to resize it.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopControl;
+    /// let lc = LoopControl::open().unwrap();
+    /// let ld = lc.next_free().unwrap();
+    /// let (ld, memfd) = ld.with().attach_memfd("scratch", 64 * 1024 * 1024).unwrap();
+    /// # ld.detach().unwrap();
+    /// # drop(memfd);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons. Either when
+    /// creating or resizing the memfd, when `name` contains a nul byte or when
+    /// calling the ioctl to attach the backing file to the device.
+    pub fn attach_memfd(self, name: &str, size: u64) -> io::Result<(LoopDevice, File)> {
+        let name =
+            CString::new(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let fd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let memfd = unsafe { File::from_raw_fd(fd as RawFd) };
+        memfd.set_len(size)?;
+
+        let device = self.device;
+        self.attach_file_handle(&memfd)?;
+        Ok((device.clone_attached()?, memfd))
+    }
+
+    /// Attach the loop device to an fd
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to attach the backing file to the device.
+    pub fn attach_fd(self, backing_file_fd: impl AsRawFd) -> io::Result<()> {
+        let mut info = loop_info64::from(&self.config.info);
+        set_file_name(&mut info, None);
+        let result = validate_config(&self.config).and_then(|_| {
+            self.device
+                .attach_fd_with_loop_info(backing_file_fd, info, &self.config)
+        });
+        self.device.record_attach(result)
+    }
+
+    /// Attach the loop device to an already opened backing file.
+    ///
+    /// Unlike [`attach`](AttachOptions::attach) the backing file is not reopened, so any flags it
+    /// was opened with (ie `O_DIRECT` or `O_TMPFILE`) are kept. The caller owns the `File` and
+    /// remains in control of its lifetime; the kernel holds its own reference for as long as the
+    /// device is attached.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use loopdev::LoopDevice;
+    /// use std::fs::OpenOptions;
+    /// let ld = LoopDevice::open("/dev/loop0").unwrap();
+    /// let file = OpenOptions::new().read(true).write(true).open("disk.img").unwrap();
+    /// ld.with().attach_file_handle(&file).unwrap();
+    /// # ld.detach().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the
+    /// ioctl to attach the backing file to the device.
+    pub fn attach_file_handle(self, backing_file: &File) -> io::Result<()> {
+        self.attach_fd(backing_file.as_raw_fd())
+    }
+}
+
+/// Used to change the options of an attached device. Created with [`LoopDevice::update`].
+///
+/// The current status of the device is read when the options are applied, so every option that
+/// is not set is preserved.
+///
+/// # Examples
+///
+/// ```no_run
+/// use loopdev::LoopDevice;
+/// let ld = LoopDevice::open("/dev/loop0").unwrap();
+/// # ld.attach_file("disk.img").unwrap();
+/// ld.update().size_limit(1024 * 1024).apply().unwrap();
+/// ```
+#[must_use]
+pub struct UpdateOptions<'d> {
+    device: &'d LoopDevice,
+    offset: Option<u64>,
+    size_limit: Option<u64>,
+    autoclear: Option<bool>,
+    #[cfg(feature = "direct_io")]
+    direct_io: Option<bool>,
+}
+
+impl UpdateOptions<'_> {
+    /// Offset in bytes from the start of the backing file. The offset can not be changed on an
+    /// attached device, so applying fails unless it matches the current offset.
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Maximum size of the data in bytes, `0` uses the whole backing file.
+    pub fn size_limit(mut self, size_limit: u64) -> Self {
+        self.size_limit = Some(size_limit);
+        self
+    }
+
+    /// Set or clear the autoclear flag.
+    pub fn autoclear(mut self, autoclear: bool) -> Self {
+        self.autoclear = Some(autoclear);
+        self
+    }
+
+    /// Enable or disable direct I/O for the backing file.
+    #[cfg(feature = "direct_io")]
+    pub fn set_direct_io(mut self, direct_io: bool) -> Self {
+        self.direct_io = Some(direct_io);
+        self
+    }
+
+    /// Write the changed options to the device.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error for various reasons when calling the ioctls to get or
+    /// set the status of the device, ie `ENXIO` if it is not attached. Changing the offset
+    /// returns an error of kind [`Unsupported`](io::ErrorKind::Unsupported) wrapping
+    /// [`Error::OffsetChangeUnsupported`](crate::Error::OffsetChangeUnsupported) without
+    /// changing anything. Enabling direct I/O fails for the same reasons as
+    /// [`LoopDevice::set_direct_io`].
+    pub fn apply(self) -> io::Result<()> {
+        let mut info = self.device.info()?;
+        if self.offset.is_some_and(|offset| offset != info.lo_offset) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                crate::Error::OffsetChangeUnsupported { attached: true },
+            ));
+        }
+        if let Some(size_limit) = self.size_limit {
+            info.lo_sizelimit = size_limit;
+        }
+        match self.autoclear {
+            Some(true) => info.lo_flags |= LO_FLAGS_AUTOCLEAR,
+            Some(false) => info.lo_flags &= !LO_FLAGS_AUTOCLEAR,
+            None => {}
+        }
+        self.device.set_info(&info)?;
+        #[cfg(feature = "direct_io")]
+        if let Some(direct_io) = self.direct_io {
+            self.device.set_direct_io(direct_io)?;
+        }
+        Ok(())
+    }
+}
+
+/// Finds the loop device that is attached to the given backing file.
+///
+/// The backing file is matched by its device and inode numbers rather than its path, so the
+/// device is found even if the file was renamed or unlinked after it was opened (as long as
+/// `backing_file` refers to the same file).
+///
+/// # Examples
+///
+/// ```no_run
+/// if let Some(ld) = loopdev::find_by_backing_file("disk.img").unwrap() {
+///     ld.detach().unwrap();
+/// }
+/// ```
+///
+/// # Errors
+///
+/// This function will return an error for various reasons. Either when
+/// getting the metadata of the backing file, when listing the loop devices in
+/// `/sys/block` or when opening one of the loop devices.
+pub fn find_by_backing_file(backing_file: impl AsRef<Path>) -> io::Result<Option<LoopDevice>> {
+    Ok(find_all_by_backing_file(backing_file)?.into_iter().next())
+}
+
+/// Finds all loop devices that are attached to the given backing file, ordered by their number.
+///
+/// Like `losetup --associated` the backing file is matched by its device and inode numbers, see
+/// [`find_by_backing_file`].
+///
+/// # Examples
+///
+/// ```no_run
+/// for ld in loopdev::find_all_by_backing_file("disk.img").unwrap() {
+///     println!("{}", ld);
+/// }
+/// ```
+///
+/// # Errors
+///
+/// This function will return an error for the same reasons as [`find_by_backing_file`].
+pub fn find_all_by_backing_file(backing_file: impl AsRef<Path>) -> io::Result<Vec<LoopDevice>> {
+    let metadata = std::fs::metadata(backing_file)?;
+    let mut devices = Vec::new();
+    for number in loop_numbers()? {
+        let device = match LoopDevice::open_number(number) {
+            Ok(device) => device,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        match device.status() {
+            Ok(info)
+                if info.backing_device == metadata.dev()
+                    && info.backing_inode == metadata.ino() =>
+            {
+                devices.push(device)
+            }
+            Ok(_) => {}
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(devices)
+}
+
+/// Open a backing file for attaching, returning the file and whether it was opened read only.
+///
+/// Like `losetup`, this falls back to opening the file read only when it cannot be opened for
+/// writing. With `direct_io` the file is opened with `O_DIRECT`, which makes the kernel use
+/// direct I/O from the start. Filesystems that do not support `O_DIRECT` (ie tmpfs) reject the
+/// open, the file is then opened buffered instead.
+fn open_backing_file(path: &Path, read_only: bool, direct_io: bool) -> io::Result<(File, bool)> {
+    let custom_flags = if direct_io { libc::O_DIRECT } else { 0 };
+    match OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .custom_flags(custom_flags)
+        .open(path)
+    {
+        Err(err) if direct_io && err.raw_os_error() == Some(libc::EINVAL) => {
+            open_backing_file(path, read_only, false)
+        }
+        Err(err)
+            if !read_only && matches!(err.raw_os_error(), Some(libc::EACCES | libc::EROFS)) =>
+        {
+            open_backing_file(path, true, direct_io)
+        }
+        result => result.map(|file| (file, read_only)),
+    }
+}
+
+/// Reject combinations of options the kernel can never satisfy before anything is attached, so
+/// attaching does not fail halfway through:
+///
+/// * a block size that is not a power of two between 512 and the page size,
+/// * direct I/O with an offset that is not a multiple of 512 bytes. No backing file has smaller
+///   blocks, so the kernel can not use direct I/O at such an offset.
+fn validate_config(config: &AttachConfig) -> io::Result<()> {
+    if let Some(block_size) = config.block_size {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+        if !block_size.is_power_of_two() || !(512..=page_size).contains(&block_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the block size {} is not a power of two between 512 and {}",
+                    block_size, page_size
+                ),
+            ));
+        }
+    }
+    // The kernel stores both as a signed `loff_t`, a larger end would wrap to a tiny device.
+    let size_limit = if config.size_limit_from_file {
+        0
+    } else {
+        config.info.size_limit
+    };
+    let end = config.info.offset.checked_add(size_limit);
+    if end.is_none_or(|end| end > i64::MAX as u64) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the offset {} and size limit {} overflow the size of a file",
+                config.info.offset, size_limit
+            ),
+        ));
+    }
+    if config.info.direct_io && !config.info.offset.is_multiple_of(512) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "direct I/O needs an offset aligned to 512 bytes, not {}",
+                config.info.offset
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Create `path` as a sparse file of `size` bytes unless it exists, returning whether it was
+/// created. An existing path must be a file or a block device.
+fn create_backing_file(path: &Path, size: u64) -> io::Result<bool> {
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(file) => {
+            if let Err(err) = file.set_len(size) {
+                let _ = std::fs::remove_file(path);
+                return Err(err);
+            }
+            Ok(true)
+        }
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            let file_type = std::fs::metadata(path)?.file_type();
+            if file_type.is_file() || file_type.is_block_device() {
+                Ok(false)
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is not a file or block device", path.display()),
+                ))
+            }
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// The length in bytes of the open backing file `fd`. Block devices report a size of zero to
+/// `fstat`, their size is asked for with `BLKGETSIZE64` instead.
+fn backing_file_size(fd: RawFd) -> io::Result<u64> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    ioctl_to_error(unsafe { libc::fstat(fd, &mut stat) })?;
+    if stat.st_mode & libc::S_IFMT == libc::S_IFBLK {
+        let mut size: u64 = 0;
+        ioctl_to_error(unsafe { ioctl(fd, BLKGETSIZE64 as IoctlRequest, &mut size) })?;
+        return Ok(size);
+    }
+    Ok(stat.st_size as u64)
+}
+
+/// Opens the loop device at `path`, detaches it from its backing file and closes it again.
+///
+/// # Examples
+///
+/// ```no_run
+/// loopdev::detach("/dev/loop3").unwrap();
+/// ```
+///
+/// # Errors
+///
+/// This function will return an error for the same reasons as [`LoopDevice::open`] and
+/// [`LoopDevice::detach`].
+pub fn detach(path: impl AsRef<Path>) -> io::Result<()> {
+    LoopDevice::open(path)?.detach()
+}
+
+/// Detaches every attached loop device that is not mounted, returning how many were detached.
+///
+/// Devices where the device itself or one of its partitions shows up in `/proc/self/mountinfo`
+/// are left alone. Note that this is not limited to devices attached by this process.
+///
+/// # Examples
+///
+/// ```no_run
+/// let detached = loopdev::detach_all().unwrap();
+/// println!("detached {} loop devices", detached);
+/// ```
+///
+/// # Errors
+///
+/// This function will return an error for various reasons. Either when
+/// listing the loop devices in `/sys/block`, when opening one of the loop
+/// devices, when reading the mounts or when calling the ioctl to detach a
+/// device. Devices that were detached before the error stay detached.
+pub fn detach_all() -> io::Result<usize> {
+    let mut detached = 0;
+    for number in loop_numbers()? {
+        let device = match LoopDevice::open_number(number) {
+            Ok(device) => device,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        match device.is_mounted() {
+            Ok(false) => {}
+            Ok(true) => continue,
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => continue,
+            Err(err) => return Err(err),
+        }
+        match device.detach() {
+            Ok(()) => detached += 1,
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(detached)
+}
+
+/// Detaches the listed device unless it is mounted, see [`LoopControl::detach_all`].
+fn detach_unmounted(device: &LoopDeviceEntry) -> io::Result<()> {
+    let ld = device.open()?;
+    let result = ld.is_mounted().and_then(|mounted| {
+        if mounted {
+            Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!("{} is mounted", device.number),
+            ))
+        } else {
+            ld.detach()
+        }
+    });
+    match result {
+        Err(err) if err.raw_os_error() == Some(libc::ENXIO) => Ok(()),
+        result => result,
+    }
+}
+
+/// Finds and opens the first free loop device among `/dev/loop0` up to but excluding
+/// `/dev/loop{max}` without the loop control device.
+///
+/// This is a compatibility path for old kernels or stripped down systems without
+/// `/dev/loop-control`, where [`LoopControl::open`] fails with `ENOENT`. It can only find
+/// existing device nodes and not create new ones, prefer [`LoopControl::next_free`] when the
+/// loop control device is available.
+///
+/// # Examples
+///
+/// ```no_run
+/// use loopdev::LoopControl;
+/// let ld = match LoopControl::open() {
+///     Ok(lc) => lc.next_free().unwrap(),
+///     Err(_) => loopdev::find_free_legacy(8).unwrap(),
+/// };
+/// println!("{}", ld.path().unwrap().display());
+/// ```
+///
+/// # Errors
+///
+/// This function will return an error for various reasons when opening one of
+/// the loop devices or when calling the ioctl to get its status. An error of
+/// kind [`NotFound`](io::ErrorKind::NotFound) is returned when none of the
+/// devices is free.
+pub fn find_free_legacy(max: u32) -> io::Result<LoopDevice> {
+    for number in 0..max {
+        let device = match LoopDevice::open_number(number) {
+            Ok(device) => device,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        if !device.is_attached()? {
+            return Ok(device);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "no free loop device below {}",
+            platform::device_path(max).display()
+        ),
+    ))
+}
+
+/// Detaches every loop device whose backing file is inside `dir`, returning the paths of the
+/// detached devices.
+///
+/// The backing file paths are read with [`LoopDevice::status`] and compared after resolving
+/// symlinks, so `dir` may itself be a symlink. Devices whose backing file was already deleted
+/// are skipped, the path the kernel reports for them no longer exists.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::Path;
+/// for path in loopdev::detach_backing_under(Path::new("/tmp/scratch")).unwrap() {
+///     println!("detached {}", path.display());
+/// }
+/// ```
+///
+/// # Errors
+///
+/// This function will return an error for various reasons. Either when
+/// resolving `dir`, when listing the loop devices in `/sys/block`, when
+/// opening one of the loop devices or when calling the ioctls to get the
+/// status of or detach a device. Devices that were detached before the error
+/// stay detached.
+pub fn detach_backing_under(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let dir = dir.canonicalize()?;
+    let mut detached = Vec::new();
+    for number in loop_numbers()? {
+        let device = match LoopDevice::open_number(number) {
+            Ok(device) => device,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        let backing_file = match device.status() {
+            Ok(info) => info.backing_file,
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => continue,
+            Err(err) => return Err(err),
+        };
+        let backing_file = match backing_file {
+            Some(backing_file) if !backing_file.as_os_str().as_bytes().ends_with(b" (deleted)") => {
+                backing_file
+            }
+            _ => continue,
+        };
+        match backing_file.canonicalize() {
+            Ok(backing_file) if backing_file.starts_with(&dir) => {}
+            Ok(_) => continue,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        }
+        match device.detach() {
+            Ok(()) => detached.push(platform::device_path(number)),
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(detached)
+}
+
+/// Explain the most common reasons the loop control device at `path` can not be opened. The
+/// kind of the error is kept, the errno is replaced by a [`crate::Error`].
+fn control_open_error(path: &Path, err: io::Error) -> io::Error {
+    let path = path.to_path_buf();
+    let error = match err.raw_os_error() {
+        Some(libc::ENOENT) => crate::Error::ControlDeviceNotFound { path },
+        Some(libc::EACCES | libc::EPERM) => crate::Error::ControlDevicePermissionDenied { path },
+        _ => return err,
+    };
+    io::Error::new(err.kind(), error)
+}
+
+/// Opens `path` for reading and writing with `O_CLOEXEC` set or cleared.
+///
+/// The standard library always adds `O_CLOEXEC` to the open flags, even with
+/// `custom_flags`, so the flag is cleared again afterwards when it is not wanted.
+fn open_with_cloexec(path: &Path, cloexec: bool) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(if cloexec { libc::O_CLOEXEC } else { 0 })
+        .open(path)?;
+    if !cloexec {
+        let fd = file.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(file)
+}
+
+/// Whether the loop device at `path` has no backing file anymore.
+fn is_detached(path: &Path) -> io::Result<bool> {
+    match LoopDevice::open(path).and_then(|ld| ld.info()) {
+        Ok(_) => Ok(false),
+        Err(err) if err.raw_os_error() == Some(libc::ENXIO) => Ok(true),
+        Err(err) => Err(err),
+    }
+}
+
+/// Create the missing node of the loop device with the given number from the device number the
+/// kernel lists in its `dev` attribute in sysfs.
+fn create_device_node(number: u32) -> io::Result<()> {
+    let dev = std::fs::read_to_string(platform::sysfs_device_dir(number).join("dev"))?;
+    let (major, minor) = dev
+        .trim()
+        .split_once(':')
+        .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid device number {:?}", dev.trim()),
+            )
+        })?;
+    let path = CString::new(platform::device_path(number).into_os_string().into_vec())?;
+    let dev = unsafe { libc::makedev(major, minor) };
+    ioctl_to_error(unsafe { libc::mknod(path.as_ptr(), libc::S_IFBLK | 0o660, dev) }).map(drop)
+}
+
+fn detach_timed_out() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        "timed out waiting for the loop device to detach",
+    )
+}
+
+/// A netlink socket receiving the uevents the kernel sends when devices change.
+struct UeventSocket(OwnedFd);
+
+impl UeventSocket {
+    fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = Self(unsafe { OwnedFd::from_raw_fd(fd) });
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        // The multicast group the kernel sends its uevents to.
+        addr.nl_groups = 1;
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                (&addr as *const libc::sockaddr_nl).cast(),
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    /// Wait up to `timeout` for a uevent of the device whose `DEVPATH` ends with
+    /// `devpath_suffix`, returning whether one arrived.
+    fn wait_for(&self, devpath_suffix: &str, timeout: Duration) -> io::Result<bool> {
+        let start = Instant::now();
+        let mut buf = [0u8; 8192];
+        loop {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            let mut pollfd = libc::pollfd {
+                fd: self.0.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ret = unsafe { libc::poll(&mut pollfd, 1, remaining.as_millis() as c_int) };
+            if ret == 0 {
+                return Ok(false);
+            }
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            let len = unsafe {
+                libc::recv(
+                    self.0.as_raw_fd(),
+                    buf.as_mut_ptr().cast(),
+                    buf.len(),
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EAGAIN | libc::EINTR) => continue,
+                    // Uevents were dropped, one of them may have been the one waited for.
+                    Some(libc::ENOBUFS) => return Ok(true),
+                    _ => return Err(err),
+                }
+            }
+            let matches = buf[..len as usize].split(|&b| b == 0).any(|field| {
+                field
+                    .strip_prefix(b"DEVPATH=")
+                    .is_some_and(|devpath| devpath.ends_with(devpath_suffix.as_bytes()))
+            });
+            if matches {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// The sorted names of the entries of `dir`.
+fn dir_entry_names(dir: &Path) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    names.sort_unstable();
+    Ok(names)
+}
+
+/// The status, size and holders of the loop device `number`, with the error that stopped
+/// probing it if any.
+fn probe_device(number: u32) -> LoopDeviceInfo {
+    let mut device = LoopDeviceInfo {
+        path: platform::device_path(number),
+        info: LoopInfo {
+            number,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let result = LoopDevice::open_number(number).and_then(|ld| {
+        match ld.snapshot() {
+            Ok(snapshot) => {
+                device.info = snapshot.info;
+                device.size = Some(snapshot.size);
+            }
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => return Ok(()),
+            Err(err) => return Err(err),
+        }
+        device.holders = ld.holders()?;
+        Ok(())
+    });
+    if let Err(err) = result {
+        device.error = Some(err.to_string());
+    }
+    device
+}
+
+/// Whether `minor` is the minor of loop device `number` itself rather than of one of its
+/// partitions, for any number of minors reserved per device (`max_part`).
+///
+/// The status ioctl is forwarded from a partition to its device, so the number of an attached
+/// device alone does not tell whether a handle is the device or a partition of it. The device
+/// has the minor `number << part_shift`, its partitions the minors right after it.
+fn is_whole_device_minor(number: u32, minor: u32) -> bool {
+    (0..u32::BITS).any(|part_shift| u64::from(number) << part_shift == u64::from(minor))
+}
+
+/// The numbers of all existing loop devices listed in `/sys/block`.
+fn loop_numbers() -> io::Result<Vec<u32>> {
+    let mut numbers = Vec::new();
+    for entry in std::fs::read_dir(platform::sysfs_root())? {
+        if let Some(number) = entry?
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("loop"))
+            .and_then(|number| number.parse().ok())
+        {
+            numbers.push(number);
+        }
+    }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+/// Copy `src` into the fixed size C string `dest`, truncating it to keep the trailing nul byte.
+fn copy_truncated(dest: &mut [u8], src: &[u8]) {
+    let len = src.len().min(dest.len() - 1);
+    dest[..len].copy_from_slice(&src[..len]);
+}
+
+/// Replace the backing file name the kernel keeps for a device with `path`, or clear it. Used
+/// when attaching so the name of the file actually attached is recorded, not the one of the
+/// `LoopInfo` the options were built from.
+fn set_file_name(info: &mut loop_info64, path: Option<&Path>) {
+    info.lo_file_name.fill(0);
+    if let Some(path) = path {
+        copy_truncated(&mut info.lo_file_name, path.as_os_str().as_bytes());
+    }
+}
+
+/// The bytes of a fixed size C string up to the first nul byte.
+fn until_nul(bytes: &[u8]) -> &[u8] {
+    bytes
+        .iter()
+        .position(|&b| b == 0)
+        .map_or(bytes, |len| &bytes[..len])
+}
+
+/// The path of an open file descriptor, used for logging.
+fn fd_path(fd: RawFd) -> PathBuf {
+    std::fs::read_link(format!("/proc/self/fd/{}", fd))
+        .unwrap_or_else(|_| format!("fd {}", fd).into())
+}
+
+fn ioctl_to_error(ret: i32) -> io::Result<i32> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_are_cleared_again() {
+        let config = AttachConfig::new()
+            .read_only(true)
+            .autoclear(true)
+            .part_scan(true);
+        #[cfg(feature = "direct_io")]
+        let config = config.set_direct_io(true);
+        let expected = LO_FLAGS_READ_ONLY | LO_FLAGS_AUTOCLEAR | LO_FLAGS_PARTSCAN;
+        #[cfg(feature = "direct_io")]
+        let expected = expected | LO_FLAGS_DIRECT_IO;
+        assert_eq!(loop_info64::from(&config.info).lo_flags, expected);
+
+        let config = config.read_only(false).autoclear(false).part_scan(false);
+        #[cfg(feature = "direct_io")]
+        let config = config.set_direct_io(false);
+        assert_eq!(loop_info64::from(&config.info).lo_flags, 0);
+    }
+
+    #[test]
+    fn the_file_name_is_replaced_when_attaching() {
+        let config = AttachConfig::new().with_info(LoopInfo {
+            backing_file: Some(PathBuf::from("/var/lib/images/other-disk.img")),
+            ..LoopInfo::default()
+        });
+        let mut info = loop_info64::from(&config.info);
+        set_file_name(&mut info, Some(Path::new("/tmp/disk.img")));
+        assert_eq!(until_nul(&info.lo_file_name), b"/tmp/disk.img");
+
+        set_file_name(&mut info, None);
+        assert_eq!(until_nul(&info.lo_file_name), b"");
+    }
+
+    #[test]
+    fn flags_match_the_kernel() {
+        assert_eq!(LoopFlags::READ_ONLY.bits(), LO_FLAGS_READ_ONLY);
+        assert_eq!(LoopFlags::AUTOCLEAR.bits(), LO_FLAGS_AUTOCLEAR);
+        assert_eq!(LoopFlags::PARTSCAN.bits(), LO_FLAGS_PARTSCAN);
+        assert_eq!(LoopFlags::DIRECT_IO.bits(), LO_FLAGS_DIRECT_IO);
+
+        let config = AttachConfig::new().flags(LoopFlags::AUTOCLEAR | LoopFlags::PARTSCAN);
+        assert_eq!(
+            loop_info64::from(&config.info).lo_flags,
+            LO_FLAGS_AUTOCLEAR | LO_FLAGS_PARTSCAN
+        );
+        assert_eq!(
+            LoopInfo::from(loop_info64::from(&config.info)).flags(),
+            LoopFlags::AUTOCLEAR | LoopFlags::PARTSCAN
+        );
+    }
+
+    #[test]
+    fn flags_are_set_independently() {
+        for (config, flag) in [
+            (AttachConfig::new().read_only(true), LO_FLAGS_READ_ONLY),
+            (AttachConfig::new().autoclear(true), LO_FLAGS_AUTOCLEAR),
+            (AttachConfig::new().part_scan(true), LO_FLAGS_PARTSCAN),
+        ] {
+            assert_eq!(loop_info64::from(&config.info).lo_flags, flag);
+        }
+    }
+
+    #[test]
+    fn status_round_trips() {
+        let info = LoopInfo {
+            number: 3,
+            backing_file: Some(PathBuf::from("/var/lib/disk.img")),
+            backing_device: 2049,
+            backing_inode: 1234,
+            backing_rdevice: 0,
+            offset: 4096,
+            size_limit: 8192,
+            read_only: true,
+            autoclear: true,
+            part_scan: false,
+            direct_io: true,
+            encrypt_type: 1,
+            crypt_name: Some(String::from("xor")),
+            init: [7, 42],
+        };
+        assert_eq!(LoopInfo::from(loop_info64::from(&info)), info);
+    }
+
+    #[test]
+    fn whole_devices_are_told_apart_from_partitions() {
+        assert!(is_whole_device_minor(0, 0));
+        assert!(is_whole_device_minor(3, 3));
+        assert!(is_whole_device_minor(3, 48));
+        assert!(!is_whole_device_minor(3, 49));
+        assert!(!is_whole_device_minor(0, 1));
+        assert!(!is_whole_device_minor(3, 4));
+        assert!(!is_whole_device_minor(1 << 19, 0));
+    }
+
+    #[test]
+    fn sectors_use_the_block_size() {
+        let config = AttachConfig::new()
+            .offset_sectors(2048)
+            .size_limit_sectors(8);
+        assert_eq!(config.info.offset, 2048 * 512);
+        assert_eq!(config.info.size_limit, 8 * 512);
+
+        let config = AttachConfig::new()
+            .block_size(4096)
+            .offset_sectors(256)
+            .size_limit_sectors(8);
+        assert_eq!(config.info.offset, 256 * 4096);
+        assert_eq!(config.info.size_limit, 8 * 4096);
+    }
+
+    #[test]
+    fn reject_overflowing_sectors() {
+        for config in [
+            AttachConfig::new().offset_sectors(u64::MAX),
+            AttachConfig::new().size_limit_sectors(u64::MAX),
+            AttachConfig::new()
+                .block_size(4096)
+                .offset_sectors(u64::MAX / 4096),
+            AttachConfig::new().offset(u64::MAX).size_limit(1),
+            AttachConfig::new().offset(1 << 62).size_limit(1 << 62),
+        ] {
+            let err = validate_config(&config).expect_err("should reject the overflow");
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        validate_config(&AttachConfig::new().offset(1 << 40).size_limit(1 << 40))
+            .expect("should accept a large offset and size limit");
+    }
+}
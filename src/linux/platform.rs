@@ -0,0 +1,73 @@
+//! Paths of the loop devices, which differ between Linux and Android. Everything that builds or
+//! parses the path of a loop device goes through here.
+use std::path::{Path, PathBuf};
+
+pub(super) const LOOP_CONTROL: &str = "/dev/loop-control";
+pub(super) const MAX_LOOP: &str = "/sys/module/loop/parameters/max_loop";
+#[cfg(not(target_os = "android"))]
+const LOOP_PREFIX: &str = "/dev/loop";
+#[cfg(target_os = "android")]
+const LOOP_PREFIX: &str = "/dev/block/loop";
+
+/// The path of the loop device with the given number, ie `/dev/loop0`.
+pub(super) fn device_path(number: u32) -> PathBuf {
+    format!("{}{}", LOOP_PREFIX, number).into()
+}
+
+/// The path of a partition of the loop device with the given number, ie `/dev/loop0p1`.
+pub(super) fn partition_path(number: u32, partition: u32) -> PathBuf {
+    format!("{}{}p{}", LOOP_PREFIX, number, partition).into()
+}
+
+/// The number of the loop device at `path`, if it is the path of a loop device.
+pub(super) fn device_number(path: &Path) -> Option<u32> {
+    path.to_str()
+        .and_then(|path| path.strip_prefix(LOOP_PREFIX))
+        .filter(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|n| n.parse().ok())
+}
+
+/// The directory the block devices are listed in.
+pub(super) fn sysfs_root() -> &'static str {
+    "/sys/block"
+}
+
+/// The sysfs directory of the loop device with the given number, ie `/sys/block/loop0`.
+pub(super) fn sysfs_device_dir(number: u32) -> PathBuf {
+    Path::new(sysfs_root()).join(format!("loop{}", number))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(target_os = "android"))]
+    #[test]
+    fn device_paths_are_under_dev() {
+        assert_eq!(device_path(3), Path::new("/dev/loop3"));
+        assert_eq!(partition_path(3, 1), Path::new("/dev/loop3p1"));
+        assert_eq!(device_number(Path::new("/dev/loop3")), Some(3));
+        assert_eq!(device_number(Path::new("/dev/block/loop3")), None);
+    }
+
+    #[cfg(target_os = "android")]
+    #[test]
+    fn device_paths_are_under_dev_block() {
+        assert_eq!(device_path(3), Path::new("/dev/block/loop3"));
+        assert_eq!(partition_path(3, 1), Path::new("/dev/block/loop3p1"));
+        assert_eq!(device_number(Path::new("/dev/block/loop3")), Some(3));
+        assert_eq!(device_number(Path::new("/dev/loop3")), None);
+    }
+
+    #[test]
+    fn device_numbers_must_be_digits() {
+        for path in ["/dev/loop-control", "/dev/loop", "/dev/null"] {
+            assert_eq!(device_number(Path::new(path)), None, "{}", path);
+        }
+        assert_eq!(
+            sysfs_device_dir(3),
+            Path::new("/sys/block/loop3"),
+            "the sysfs directory should match"
+        );
+    }
+}
@@ -0,0 +1,1074 @@
+//! Stand in for the loop device interface on platforms other than Linux and Android.
+//!
+//! This exposes the same API so that code using loopdev can be checked and compiled on any
+//! platform. Nothing can be opened, every constructor returns an error of kind
+//! [`Unsupported`](io::ErrorKind::Unsupported) and as such none of the types can be created.
+use crate::{AttachConfig, Geometry, LoopDeviceInfo, LoopFlags, LoopInfo, LoopSnapshot};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::{
+    convert::Infallible,
+    fmt,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+fn unsupported<T>() -> io::Result<T> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "loop devices are only supported on Linux",
+    ))
+}
+
+/// Interface to the loop control device: `/dev/loop-control`.
+#[derive(Debug)]
+pub struct LoopControl {
+    never: Infallible,
+}
+
+impl LoopControl {
+    /// Opens the loop control device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn open() -> io::Result<Self> {
+        unsupported()
+    }
+
+    pub fn open_at<P: AsRef<Path>>(_path: P) -> io::Result<Self> {
+        unsupported()
+    }
+
+    pub fn open_with_flags(_cloexec: bool) -> io::Result<Self> {
+        unsupported()
+    }
+
+    pub fn open_read_only() -> io::Result<Self> {
+        unsupported()
+    }
+
+    /// Opens the loop control device, waiting for it to appear.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn open_timeout(_timeout: Duration) -> io::Result<Self> {
+        unsupported()
+    }
+
+    /// Finds and opens the next available loop device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn next_free(&self) -> io::Result<LoopDevice> {
+        match self.never {}
+    }
+
+    /// Finds and opens the next available loop device, also returning its number.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn next_free_with_number(&self) -> io::Result<(u32, LoopDevice)> {
+        match self.never {}
+    }
+
+    /// Finds and opens the next available loop device while holding an exclusive advisory lock
+    /// on the loop control device until the returned guard is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn next_free_locked(&self) -> io::Result<LockedLoopDevice> {
+        match self.never {}
+    }
+
+    /// Finds and opens `count` distinct available loop devices.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn next_free_n(&self, _count: usize) -> io::Result<Vec<LoopDevice>> {
+        match self.never {}
+    }
+
+    /// Finds and opens the first available loop device numbered `min` or above.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn next_free_from(&self, _min: impl Into<DeviceNumber>) -> io::Result<LoopDevice> {
+        match self.never {}
+    }
+
+    /// Finds the number of the next available loop device without opening it.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn find_free_number(&self) -> io::Result<u32> {
+        match self.never {}
+    }
+
+    pub fn supports_configure(&self) -> bool {
+        match self.never {}
+    }
+
+    /// Report every loop device with its status, size and holders.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn probe(&self) -> io::Result<Vec<LoopDeviceInfo>> {
+        match self.never {}
+    }
+
+    /// List every loop device with its number and whether it is attached.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn list(&self) -> io::Result<LoopDevices> {
+        match self.never {}
+    }
+
+    /// Detaches every attached loop device that is not mounted, returning the result for each.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn detach_all(&self) -> io::Result<Vec<(DeviceNumber, io::Result<()>)>> {
+        match self.never {}
+    }
+
+    /// Add and opens a new loop device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn add(&self, _n: impl Into<DeviceNumber>) -> io::Result<LoopDevice> {
+        match self.never {}
+    }
+
+    /// Removes the loop device with the given number.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn remove(&self, _n: impl Into<DeviceNumber>) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// Opens the loop device with the given number, adding it if it does not exist yet.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn open_device(&self, _n: impl Into<DeviceNumber>) -> io::Result<LoopDevice> {
+        match self.never {}
+    }
+
+    /// Get the `max_loop` parameter of the loop driver.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn max_devices() -> io::Result<Option<u32>> {
+        unsupported()
+    }
+
+    /// Creates a new independently owned handle to the loop control device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self.never {}
+    }
+
+    /// Wrap the loop control device so that the devices it adds are removed when it is dropped.
+    pub fn scoped(self) -> ScopedLoopControl {
+        match self.never {}
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for LoopControl {
+    fn as_raw_fd(&self) -> RawFd {
+        match self.never {}
+    }
+}
+
+#[cfg(unix)]
+impl IntoRawFd for LoopControl {
+    fn into_raw_fd(self) -> RawFd {
+        match self.never {}
+    }
+}
+
+#[cfg(unix)]
+impl FromRawFd for LoopControl {
+    /// Wraps an already open loop control device.
+    ///
+    /// # Safety
+    ///
+    /// There is no loop control device on this platform so this always panics.
+    unsafe fn from_raw_fd(_fd: RawFd) -> Self {
+        panic!("loop devices are only supported on Linux")
+    }
+}
+
+impl fmt::Display for LoopControl {
+    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.never {}
+    }
+}
+
+/// A loop control device that removes the devices it added when it is dropped.
+#[derive(Debug)]
+pub struct ScopedLoopControl {
+    control: LoopControl,
+}
+
+impl ScopedLoopControl {
+    /// Add and open a new loop device, removing it again when this is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn add(&self, _n: impl Into<DeviceNumber>) -> io::Result<LoopDevice> {
+        match self.control.never {}
+    }
+}
+
+impl std::ops::Deref for ScopedLoopControl {
+    type Target = LoopControl;
+
+    fn deref(&self) -> &LoopControl {
+        &self.control
+    }
+}
+
+/// A free loop device that holds the lock taken by [`LoopControl::next_free_locked`].
+#[derive(Debug)]
+pub struct LockedLoopDevice {
+    device: LoopDevice,
+}
+
+impl LockedLoopDevice {
+    /// Release the lock and return the device.
+    pub fn into_inner(self) -> LoopDevice {
+        self.device
+    }
+}
+
+impl std::ops::Deref for LockedLoopDevice {
+    type Target = LoopDevice;
+
+    fn deref(&self) -> &LoopDevice {
+        &self.device
+    }
+}
+
+/// A loop device listed by [`LoopControl::list`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopDeviceEntry {
+    /// The number of the device.
+    pub number: DeviceNumber,
+    /// Whether the device is attached to a backing file.
+    pub attached: bool,
+}
+
+impl LoopDeviceEntry {
+    /// The path of the device, ie `/dev/loop3`.
+    pub fn path(&self) -> PathBuf {
+        self.number.path()
+    }
+
+    /// Opens the device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn open(&self) -> io::Result<LoopDevice> {
+        unsupported()
+    }
+}
+
+/// Iterator over the loop devices. Created with [`LoopControl::list`].
+#[derive(Debug)]
+pub struct LoopDevices {
+    never: Infallible,
+}
+
+impl Iterator for LoopDevices {
+    type Item = LoopDeviceEntry;
+
+    fn next(&mut self) -> Option<LoopDeviceEntry> {
+        match self.never {}
+    }
+}
+
+/// The number of a loop device, ie `3` for `/dev/loop3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DeviceNumber(pub u32);
+
+impl DeviceNumber {
+    /// The path of the device, ie `/dev/loop3`.
+    pub fn path(self) -> PathBuf {
+        format!("/dev/loop{}", self.0).into()
+    }
+}
+
+impl From<u32> for DeviceNumber {
+    fn from(number: u32) -> Self {
+        Self(number)
+    }
+}
+
+impl From<DeviceNumber> for u32 {
+    fn from(number: DeviceNumber) -> Self {
+        number.0
+    }
+}
+
+impl fmt::Display for DeviceNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path().display())
+    }
+}
+
+impl std::str::FromStr for DeviceNumber {
+    type Err = io::Error;
+
+    /// Parses a device number, ie `3`, or the path of a loop device, ie `/dev/loop3`.
+    fn from_str(s: &str) -> io::Result<Self> {
+        s.strip_prefix("/dev/loop")
+            .unwrap_or(s)
+            .parse()
+            .map(Self)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is not a loop device number or path", s),
+                )
+            })
+    }
+}
+
+/// Interface to a loop device ie `/dev/loop0`.
+#[derive(Debug)]
+pub struct LoopDevice {
+    never: Infallible,
+}
+
+#[cfg(unix)]
+impl AsRawFd for LoopDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        match self.never {}
+    }
+}
+
+#[cfg(unix)]
+impl IntoRawFd for LoopDevice {
+    fn into_raw_fd(self) -> RawFd {
+        match self.never {}
+    }
+}
+
+#[cfg(unix)]
+impl FromRawFd for LoopDevice {
+    /// Wraps an already open loop device.
+    ///
+    /// # Safety
+    ///
+    /// There are no loop devices on this platform so this always panics.
+    unsafe fn from_raw_fd(_fd: RawFd) -> Self {
+        panic!("loop devices are only supported on Linux")
+    }
+}
+
+impl TryFrom<&Path> for LoopDevice {
+    type Error = io::Error;
+
+    /// Opens a loop device, checking that the path names a loop device.
+    fn try_from(_path: &Path) -> io::Result<Self> {
+        unsupported()
+    }
+}
+
+impl io::Read for LoopDevice {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        match self.never {}
+    }
+}
+
+impl io::Seek for LoopDevice {
+    fn seek(&mut self, _pos: io::SeekFrom) -> io::Result<u64> {
+        match self.never {}
+    }
+}
+
+impl PartialEq for LoopDevice {
+    /// Compares the device nodes of both handles.
+    fn eq(&self, _other: &Self) -> bool {
+        match self.never {}
+    }
+}
+
+impl fmt::Display for LoopDevice {
+    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.never {}
+    }
+}
+
+impl LoopDevice {
+    /// Opens a loop device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn open<P: AsRef<Path>>(_dev: P) -> io::Result<Self> {
+        unsupported()
+    }
+
+    pub fn open_with_flags<P: AsRef<Path>>(_dev: P, _cloexec: bool) -> io::Result<Self> {
+        unsupported()
+    }
+
+    /// Opens a loop device by its number, ie `0` for `/dev/loop0`.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn open_number(_n: impl Into<DeviceNumber>) -> io::Result<Self> {
+        unsupported()
+    }
+
+    /// Creates a new independently owned handle to the same loop device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self.never {}
+    }
+
+    /// Consumes the `LoopDevice` and returns the `File` of the device.
+    pub fn into_file(self) -> File {
+        match self.never {}
+    }
+
+    /// Attach the loop device to a file with given options.
+    pub fn with(&self) -> AttachOptions<'_> {
+        match self.never {}
+    }
+
+    /// Change the options of an attached device.
+    pub fn update(&self) -> UpdateOptions<'_> {
+        match self.never {}
+    }
+
+    /// Attach `target` to `backing_file` configured like this device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn clone_config_to(&self, _target: &LoopDevice, _backing_file: &Path) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// Attach the loop device to a file that maps to the whole file.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn attach_file<P: AsRef<Path>>(&self, _backing_file: P) -> io::Result<()> {
+        match self.never {}
+    }
+
+    pub fn attach_range<P: AsRef<Path>>(
+        &self,
+        _backing_file: P,
+        _offset: u64,
+        _len: u64,
+    ) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// Attach the loop device to a file with the options of `config`.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn attach_with_config(
+        &self,
+        _backing_file: impl AsRef<Path>,
+        _config: &AttachConfig,
+    ) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// Get the status of the loop device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn status(&self) -> io::Result<LoopInfo> {
+        match self.never {}
+    }
+
+    /// Check whether a backing file is attached to the loop device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn is_attached(&self) -> io::Result<bool> {
+        match self.never {}
+    }
+
+    /// Get the path of the backing file, `None` when the device is not attached.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn backing_file(&self) -> io::Result<Option<PathBuf>> {
+        match self.never {}
+    }
+
+    /// Get the status, size and logical block size of the loop device in one go.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn snapshot(&self) -> io::Result<LoopSnapshot> {
+        match self.never {}
+    }
+
+    /// Get the offset in bytes from the start of the backing file the data starts at.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn offset(&self) -> io::Result<u64> {
+        match self.never {}
+    }
+
+    /// Get the maximum size of the data in bytes, `0` means the whole backing file is used.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn size_limit(&self) -> io::Result<u64> {
+        match self.never {}
+    }
+
+    /// Get the paths of the partitions of the loop device, ordered by partition number.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn partitions(&self) -> io::Result<Vec<PathBuf>> {
+        match self.never {}
+    }
+
+    /// Wait until the device has at least `expected` partitions.
+    pub fn wait_for_partitions(
+        &self,
+        _expected: usize,
+        _timeout: Duration,
+    ) -> io::Result<Vec<PathBuf>> {
+        match self.never {}
+    }
+
+    /// Get the path of the loop device.
+    pub fn path(&self) -> Option<PathBuf> {
+        match self.never {}
+    }
+
+    pub fn number(&self) -> io::Result<u32> {
+        match self.never {}
+    }
+
+    /// Get the device major number
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn major(&self) -> io::Result<u32> {
+        match self.never {}
+    }
+
+    /// Get the device minor number
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn minor(&self) -> io::Result<u32> {
+        match self.never {}
+    }
+
+    /// Detach a loop device from its backing file.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn detach(&self) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// Detach the device if it is attached, doing nothing if it is not.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn ensure_detached(&self) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// The names of the devices holding this device.
+    pub fn holders(&self) -> io::Result<Vec<String>> {
+        match self.never {}
+    }
+
+    /// The names of the devices this device is built on.
+    pub fn slaves(&self) -> io::Result<Vec<String>> {
+        match self.never {}
+    }
+
+    /// Detach a loop device from its backing file and wait until it is fully detached.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn detach_and_wait(self, _timeout: Duration) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// Detach a loop device from its backing file and wait for the kernel to announce that it is
+    /// fully detached.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn detach_and_wait_uevent(self, _timeout: Duration) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// Resize a live loop device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn set_capacity(&self) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// Resize a live loop device and check it picked up the expected size.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn set_capacity_checked(&self, _expected: u64) -> io::Result<u64> {
+        match self.never {}
+    }
+
+    /// Get the size of the device in bytes.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn size(&self) -> io::Result<u64> {
+        match self.never {}
+    }
+
+    /// Check whether the device is read only at the block layer.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn is_read_only(&self) -> io::Result<bool> {
+        match self.never {}
+    }
+
+    /// Check whether the autoclear flag is set.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn is_autoclear(&self) -> io::Result<bool> {
+        match self.never {}
+    }
+
+    /// Check whether the kernel scans the partition table of the device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn is_part_scan(&self) -> io::Result<bool> {
+        match self.never {}
+    }
+
+    /// Get the logical block size of the device in bytes.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn block_size(&self) -> io::Result<u32> {
+        match self.never {}
+    }
+
+    /// Get the physical block size of the device in bytes as reported by the kernel.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn physical_block_size(&self) -> io::Result<u32> {
+        match self.never {}
+    }
+
+    /// Get the read-ahead of the device in KiB.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn read_ahead_kb(&self) -> io::Result<u32> {
+        match self.never {}
+    }
+
+    /// Set the read-ahead of the device in KiB.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn set_read_ahead_kb(&self, _kb: u32) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// Get the size, logical and physical block size of the device in one go.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn geometry(&self) -> io::Result<Geometry> {
+        match self.never {}
+    }
+
+    /// Refuse to change the offset of the device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn try_set_offset(&self, _offset: u64) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// Check whether the device accepts discard (TRIM) requests.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn discard_supported(&self) -> io::Result<bool> {
+        match self.never {}
+    }
+
+    /// Set the logical block size of the device in bytes.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn set_block_size(&self, _block_size: u32) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// Re-read the partition table of the device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn reread_partitions(&self) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// Enable or disable the autoclear flag on an attached device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn set_autoclear(&self, _enable: bool) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// Change the maximum size of the data in bytes on an attached device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn set_size_limit(&self, _size_limit: u64) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// Enable or disable direct I/O for the backing file.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    #[cfg(feature = "direct_io")]
+    pub fn set_direct_io(&self, _direct_io: bool) -> io::Result<()> {
+        match self.never {}
+    }
+
+    /// Check whether direct I/O is enabled for the backing file.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    #[cfg(feature = "direct_io")]
+    pub fn direct_io(&self) -> io::Result<bool> {
+        match self.never {}
+    }
+}
+
+/// Used to set options when attaching a device. Created with [`LoopDevice::with`()].
+#[must_use]
+pub struct AttachOptions<'d> {
+    device: &'d LoopDevice,
+}
+
+impl AttachOptions<'_> {
+    /// Replace all options with the given status.
+    pub fn with_info(self, _info: LoopInfo) -> Self {
+        match self.device.never {}
+    }
+
+    /// Replace all options with the given config.
+    pub fn with_config(self, _config: AttachConfig) -> Self {
+        match self.device.never {}
+    }
+
+    /// The options that will be used to attach the device.
+    pub fn config(&self) -> &AttachConfig {
+        match self.device.never {}
+    }
+
+    /// Offset in bytes from the start of the backing file the data will start at.
+    pub fn offset(self, _offset: u64) -> Self {
+        match self.device.never {}
+    }
+
+    /// Maximum size of the data in bytes.
+    pub fn size_limit(self, _size_limit: u64) -> Self {
+        match self.device.never {}
+    }
+
+    /// Offset in sectors from the start of the backing file the data will start at.
+    pub fn offset_sectors(self, _lba: u64) -> Self {
+        match self.device.never {}
+    }
+
+    /// Maximum size of the data in sectors.
+    pub fn size_limit_sectors(self, _count: u64) -> Self {
+        match self.device.never {}
+    }
+
+    /// Set read only flag
+    pub fn read_only(self, _read_only: bool) -> Self {
+        match self.device.never {}
+    }
+
+    /// Set autoclear flag
+    pub fn autoclear(self, _autoclear: bool) -> Self {
+        match self.device.never {}
+    }
+
+    /// Enable or disable direct I/O for the backing file.
+    #[cfg(feature = "direct_io")]
+    pub fn set_direct_io(self, _direct_io: bool) -> Self {
+        match self.device.never {}
+    }
+
+    /// Logical block size of the device in bytes.
+    pub fn block_size(self, _block_size: u32) -> Self {
+        match self.device.never {}
+    }
+
+    /// Force the kernel to scan the partition table on a newly created loop device.
+    pub fn part_scan(self, _enable: bool) -> Self {
+        match self.device.never {}
+    }
+
+    /// Replace all flags at once.
+    pub fn flags(self, _flags: LoopFlags) -> Self {
+        match self.device.never {}
+    }
+
+    /// Take an exclusive advisory lock on the backing file for as long as the device is attached.
+    pub fn exclusive(self, _exclusive: bool) -> Self {
+        match self.device.never {}
+    }
+
+    /// Set the size limit to the current length of the backing file after the offset.
+    pub fn size_limit_from_file(self, _enable: bool) -> Self {
+        match self.device.never {}
+    }
+
+    /// Replace the backing file if the device is already attached.
+    pub fn replace(self, _replace: bool) -> Self {
+        match self.device.never {}
+    }
+
+    /// Create the backing file with the given size if it does not exist.
+    pub fn create_backing(self, _size: u64) -> Self {
+        match self.device.never {}
+    }
+
+    /// Attach the loop device to a file with the set options.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn attach(self, _backing_file: impl AsRef<Path>) -> io::Result<()> {
+        match self.device.never {}
+    }
+
+    /// Attach the loop device to a file with the set options and return an owned handle to the
+    /// attached device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn into_attached(self, _backing_file: impl AsRef<Path>) -> io::Result<LoopDevice> {
+        match self.device.never {}
+    }
+
+    /// Attach the loop device to a file with the set options and return an owned handle to the
+    /// attached device together with the opened backing file.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn attach_owned(self, _backing_file: impl AsRef<Path>) -> io::Result<(LoopDevice, File)> {
+        match self.device.never {}
+    }
+
+    /// Create an anonymous in-memory file and attach the loop device to it with the set options.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn attach_memfd(self, _name: &str, _size: u64) -> io::Result<(LoopDevice, File)> {
+        match self.device.never {}
+    }
+
+    /// Attach the loop device to an fd
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    #[cfg(unix)]
+    pub fn attach_fd(self, _backing_file_fd: impl AsRawFd) -> io::Result<()> {
+        match self.device.never {}
+    }
+
+    /// Attach the loop device to an already opened backing file.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn attach_file_handle(self, _backing_file: &File) -> io::Result<()> {
+        match self.device.never {}
+    }
+}
+
+/// Used to change the options of an attached device. Created with [`LoopDevice::update`].
+#[must_use]
+pub struct UpdateOptions<'d> {
+    device: &'d LoopDevice,
+}
+
+impl UpdateOptions<'_> {
+    pub fn offset(self, _offset: u64) -> Self {
+        match self.device.never {}
+    }
+
+    pub fn size_limit(self, _size_limit: u64) -> Self {
+        match self.device.never {}
+    }
+
+    pub fn autoclear(self, _autoclear: bool) -> Self {
+        match self.device.never {}
+    }
+
+    #[cfg(feature = "direct_io")]
+    pub fn set_direct_io(self, _direct_io: bool) -> Self {
+        match self.device.never {}
+    }
+
+    /// Write the changed options to the device.
+    ///
+    /// # Errors
+    ///
+    /// Always returns an error as loop devices are not supported on this platform.
+    pub fn apply(self) -> io::Result<()> {
+        match self.device.never {}
+    }
+}
+
+/// Finds the loop device that is attached to the given backing file.
+///
+/// # Errors
+///
+/// Always returns an error as loop devices are not supported on this platform.
+pub fn find_by_backing_file(_backing_file: impl AsRef<Path>) -> io::Result<Option<LoopDevice>> {
+    unsupported()
+}
+
+/// Finds all loop devices that are attached to the given backing file.
+///
+/// # Errors
+///
+/// Always returns an error as loop devices are not supported on this platform.
+pub fn find_all_by_backing_file(_backing_file: impl AsRef<Path>) -> io::Result<Vec<LoopDevice>> {
+    unsupported()
+}
+
+/// Opens the loop device at `path`, detaches it from its backing file and closes it again.
+///
+/// # Errors
+///
+/// Always returns an error as loop devices are not supported on this platform.
+pub fn detach(_path: impl AsRef<Path>) -> io::Result<()> {
+    unsupported()
+}
+
+/// Detaches every attached loop device that is not mounted, returning how many were detached.
+///
+/// # Errors
+///
+/// Always returns an error as loop devices are not supported on this platform.
+pub fn detach_all() -> io::Result<usize> {
+    unsupported()
+}
+
+/// Finds and opens the first free loop device among `/dev/loop0` up to but excluding
+/// `/dev/loop{max}` without the loop control device.
+///
+/// # Errors
+///
+/// Always returns an error as loop devices are not supported on this platform.
+pub fn find_free_legacy(_max: u32) -> io::Result<LoopDevice> {
+    unsupported()
+}
+
+/// Detaches every loop device whose backing file is inside `dir`.
+///
+/// # Errors
+///
+/// Always returns an error as loop devices are not supported on this platform.
+pub fn detach_backing_under(_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    unsupported()
+}
@@ -1,109 +1,2335 @@
-use loopdev::{LoopControl, LoopDevice};
-use std::path::PathBuf;
+use gpt::disk::LogicalBlockSize;
+use loopdev::{AttachConfig, DeviceNumber, LoopControl, LoopDevice, LoopFlags};
+use std::{
+    io::{Read, Seek, SeekFrom},
+    os::unix::{
+        fs::{MetadataExt, PermissionsExt},
+        io::{AsRawFd, FromRawFd, IntoRawFd},
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 mod util;
 use crate::util::{
-    attach_file, create_backing_file, detach_all, list_device, partition_backing_file, setup,
+    attach_file, create_backing_file, create_backing_file_in, detach_all, list_device,
+    partition_backing_file, partition_backing_file_n, setup,
 };
+#[cfg(feature = "direct_io")]
+use crate::util::{disk_backed_dir, tmpfs_dir};
 
 #[test]
 fn get_next_free_device() {
-    let num_devices_at_start = list_device(None).len();
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let ld0 = lc
+        .next_free()
+        .expect("should not error finding the next free loopback device");
+
+    assert_eq!(
+        ld0.path(),
+        Some(PathBuf::from(&format!("/dev/loop{}", num_devices_at_start))),
+        "should find the first loopback device"
+    );
+}
+
+#[test]
+fn get_next_free_device_with_its_number() {
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let (number, ld0) = lc
+        .next_free_with_number()
+        .expect("should not error finding the next free loopback device");
+
+    assert_eq!(
+        number as usize, num_devices_at_start,
+        "should find the first loopback device number"
+    );
+    assert_eq!(
+        ld0.path(),
+        Some(DeviceNumber(number).path()),
+        "should open the device with that number"
+    );
+}
+
+#[test]
+fn create_the_missing_node_of_the_next_free_device() {
+    let _lock = setup();
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let number = lc
+        .find_free_number()
+        .expect("should not error finding the next free loopback device");
+    let path = PathBuf::from(format!("/dev/loop{}", number));
+    let rdev = std::fs::metadata(&path)
+        .expect("the kernel should create the node")
+        .rdev();
+    std::fs::remove_file(&path).expect("should be able to remove the node");
+
+    let result = lc.next_free_with_number();
+    let metadata = std::fs::metadata(&path);
+
+    let (free_number, ld0) = result.expect("should not error opening the next free device");
+    assert_eq!(free_number, number, "should find the same device");
+    assert_eq!(ld0.path(), Some(path), "should open the recreated node");
+    assert_eq!(
+        metadata.map(|metadata| metadata.rdev()).ok(),
+        Some(rdev),
+        "should recreate the node with the device number of the device"
+    );
+}
+
+#[test]
+fn get_next_free_device_locked() {
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let ld0 = lc
+        .next_free_locked()
+        .expect("should not error finding the next free loopback device");
+
+    assert_eq!(
+        ld0.path(),
+        Some(PathBuf::from(&format!("/dev/loop{}", num_devices_at_start))),
+        "should find the first loopback device"
+    );
+}
+
+#[test]
+fn hold_the_allocation_lock_until_the_device_is_attached() {
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    let (waited, second) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(128 * 1024 * 1024);
+        let first = lc
+            .next_free_locked()
+            .expect("should not error finding the next free loopback device");
+
+        std::thread::scope(|scope| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            let lc = &lc;
+            scope.spawn(move || {
+                let second = lc
+                    .next_free_locked()
+                    .map(|ld| ld.into_inner().path())
+                    .expect("should not error finding the next free loopback device");
+                sender.send(second).unwrap();
+            });
+
+            let waited = receiver.recv_timeout(Duration::from_millis(200)).is_err();
+            first
+                .attach_file(&file)
+                .expect("should not error attaching the backing file to the loopdev");
+            drop(first);
+            (waited, receiver.recv().unwrap())
+        })
+    };
+
+    assert!(
+        waited,
+        "should wait for the lock held by the other allocation"
+    );
+    assert_eq!(
+        second,
+        Some(PathBuf::from(&format!(
+            "/dev/loop{}",
+            num_devices_at_start + 1
+        ))),
+        "should find the next device after the attached one"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn get_several_free_devices() {
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let devices = lc
+        .next_free_n(3)
+        .expect("should not error finding the next free loopback devices");
+
+    assert_eq!(
+        devices.iter().map(|ld| ld.path()).collect::<Vec<_>>(),
+        (num_devices_at_start..num_devices_at_start + 3)
+            .map(|n| Some(PathBuf::from(format!("/dev/loop{}", n))))
+            .collect::<Vec<_>>(),
+        "should find distinct loopback devices"
+    );
+}
+
+#[test]
+fn get_the_next_free_device_from_a_number() {
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    let min = num_devices_at_start as u32 + 2;
+    let file = create_backing_file(128 * 1024 * 1024);
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let ld0 = lc
+        .next_free_from(min)
+        .expect("should not error finding the next free loopback device");
+    assert_eq!(
+        ld0.path(),
+        Some(PathBuf::from(format!("/dev/loop{}", min))),
+        "should find the device with the minimum number"
+    );
+
+    ld0.attach_file(&file)
+        .expect("should not error attaching the backing file");
+    assert!(
+        ld0.is_attached()
+            .expect("should not error checking the device"),
+        "should report the device as attached"
+    );
+    let ld1 = lc
+        .next_free_from(min)
+        .expect("should not error finding the next free loopback device");
+    assert_eq!(
+        ld1.path(),
+        Some(PathBuf::from(format!("/dev/loop{}", min + 1))),
+        "should skip the attached device"
+    );
+
+    ld0.detach().expect("should not error detaching the device");
+    file.close().expect("should delete the temp backing file");
+}
+
+#[test]
+fn open_a_device_that_does_not_exist_yet() {
+    let _lock = setup();
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let number = glob::glob("/sys/block/loop*")
+        .unwrap()
+        .filter_map(|entry| {
+            entry.ok()?.file_name()?.to_str()?["loop".len()..]
+                .parse::<u32>()
+                .ok()
+        })
+        .max()
+        .map_or(0, |max| max + 1);
+    let ld0 = lc
+        .open_device(number)
+        .expect("should not error opening the loopback device");
+
+    assert_eq!(
+        ld0.path(),
+        Some(PathBuf::from(format!("/dev/loop{}", number))),
+        "should open the requested loopback device"
+    );
+}
+
+#[test]
+fn remove_an_added_device_when_attaching_fails() {
+    let _lock = setup();
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let number = glob::glob("/sys/block/loop*")
+        .unwrap()
+        .filter_map(|entry| {
+            entry.ok()?.file_name()?.to_str()?["loop".len()..]
+                .parse::<u32>()
+                .ok()
+        })
+        .max()
+        .map_or(0, |max| max + 1);
+    let ld0 = lc
+        .open_device(number)
+        .expect("should not error opening the loopback device");
+    let added = Path::new(&format!("/sys/block/loop{}", number)).exists();
+
+    let dir = tempfile::tempdir().expect("should be able to create a temp dir");
+    let result = ld0.attach_file(dir.path().join("missing.img"));
+    let usable = ld0.is_attached();
+    drop(ld0);
+
+    assert!(added, "should add the loopback device");
+    assert!(result.is_err(), "should not attach a missing backing file");
+    assert!(
+        matches!(usable, Ok(false)),
+        "should keep the handle usable until it is dropped"
+    );
+    assert!(
+        !Path::new(&format!("/sys/block/loop{}", number)).exists(),
+        "should remove the added loopback device again"
+    );
+}
+
+#[test]
+fn open_a_device_by_a_parsed_device_number() {
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let path = format!("/dev/loop{}", num_devices_at_start);
+    let number = path
+        .parse::<DeviceNumber>()
+        .expect("should parse the path of a loopback device");
+    let ld0 = lc
+        .open_device(number)
+        .expect("should not error opening the loopback device");
+
+    assert_eq!(
+        number,
+        DeviceNumber(num_devices_at_start as u32),
+        "should parse the number of the device"
+    );
+    assert_eq!(number.to_string(), path, "should display the path");
+    assert_eq!(
+        ld0.path(),
+        Some(PathBuf::from(path)),
+        "should open the requested loopback device"
+    );
+}
+
+#[test]
+fn get_the_maximum_number_of_devices() {
+    let max_loop = std::fs::read_to_string("/sys/module/loop/parameters/max_loop")
+        .expect("should be able to read max_loop")
+        .trim()
+        .parse::<u32>()
+        .unwrap();
+
+    assert_eq!(
+        LoopControl::max_devices().expect("should not error reading max_loop"),
+        Some(max_loop).filter(|&max| max > 0),
+        "should match the max_loop parameter"
+    );
+}
+
+#[test]
+fn find_a_free_device_without_the_loop_control_device() {
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    let file = create_backing_file(128 * 1024 * 1024);
+    let ld0 = loopdev::find_free_legacy(num_devices_at_start as u32 + 2)
+        .expect("should not error finding a free loopback device");
+    assert_eq!(
+        ld0.path(),
+        Some(PathBuf::from(format!("/dev/loop{}", num_devices_at_start))),
+        "should find the first free loopback device"
+    );
+    ld0.attach_file(&file)
+        .expect("should not error attaching the backing file");
+    let none_free = loopdev::find_free_legacy(num_devices_at_start as u32 + 1);
+
+    ld0.detach().expect("should not error detaching the device");
+    file.close().expect("should delete the temp backing file");
+    assert_eq!(
+        none_free
+            .expect_err("should error when no device is free")
+            .kind(),
+        std::io::ErrorKind::NotFound,
+        "should report that no device was found"
+    );
+}
+
+#[test]
+fn open_the_loop_control_device_with_a_timeout() {
+    let _lock = setup();
+
+    let lc = LoopControl::open_timeout(Duration::from_secs(1))
+        .expect("should be able to open the LoopControl device");
+    lc.find_free_number()
+        .expect("should not error finding the next free loopback device number");
+}
+
+#[test]
+fn open_the_loop_control_device_at_another_path() {
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    let dir = tempfile::tempdir().expect("should be able to create a temp dir");
+    let path = dir.path().join("loop-control");
+    std::os::unix::fs::symlink("/dev/loop-control", &path)
+        .expect("should be able to link the LoopControl device");
+
+    let lc = LoopControl::open_at(&path).expect("should be able to open the LoopControl device");
+    let number = lc
+        .find_free_number()
+        .expect("should not error finding the next free loopback device number");
+    let missing = LoopControl::open_at(dir.path().join("missing"));
+
+    assert_eq!(
+        number as usize, num_devices_at_start,
+        "should find the first loopback device number"
+    );
+    assert_eq!(
+        missing.map(drop).map_err(|err| err.kind()),
+        Err(std::io::ErrorKind::NotFound),
+        "should not open a missing LoopControl device"
+    );
+}
+
+#[test]
+fn explain_why_the_loop_control_device_can_not_be_opened() {
+    let dir = tempfile::tempdir().expect("should be able to create a temp dir");
+    let path = dir.path().join("missing");
+
+    let err = LoopControl::open_at(&path).expect_err("should not open a missing device");
+
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<loopdev::Error>()),
+        Some(&loopdev::Error::ControlDeviceNotFound { path: path.clone() }),
+        "should return the typed error"
+    );
+    assert!(
+        err.to_string().contains("modprobe loop"),
+        "should suggest loading the loop module"
+    );
+}
+
+#[test]
+fn list_all_devices() {
+    let _lock = setup();
+
+    let (devices, attached, free) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(64 * 1024 * 1024);
+        let (attached, ld0) = lc
+            .next_free_with_number()
+            .expect("should not error finding the next free loopback device");
+        ld0.attach_file(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+        let (free, _) = lc
+            .next_free_with_number()
+            .expect("should not error finding the next free loopback device");
+
+        let devices = lc
+            .list()
+            .expect("should not error listing the devices")
+            .collect::<Vec<_>>();
+
+        ld0.detach()
+            .expect("should not error detaching the backing file");
+        file.close().expect("should delete the temp backing file");
+        (devices, attached, free)
+    };
+
+    assert!(
+        devices
+            .windows(2)
+            .all(|pair| pair[0].number < pair[1].number),
+        "should list the devices ordered by number"
+    );
+    let find = |number| {
+        devices
+            .iter()
+            .find(|device| device.number == DeviceNumber(number))
+            .copied()
+    };
+    assert_eq!(
+        find(attached).map(|device| device.attached),
+        Some(true),
+        "should list the attached device as attached"
+    );
+    assert_eq!(
+        find(free).map(|device| device.attached),
+        Some(false),
+        "should list the free device as free"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn probe_with_a_read_only_loop_control_device() {
+    let _lock = setup();
+
+    let (devices, ld0_path) = {
+        let file = create_backing_file(64 * 1024 * 1024);
+        let ld0 = LoopControl::open()
+            .expect("should be able to open the LoopControl device")
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.attach_file(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+
+        let lc = LoopControl::open_read_only()
+            .expect("should be able to open the LoopControl device read only");
+        let devices = lc.probe().expect("should not error probing the devices");
+
+        ld0.detach()
+            .expect("should not error detaching the backing file");
+        file.close().expect("should delete the temp backing file");
+        (devices, ld0.path().unwrap())
+    };
+
+    assert!(
+        devices
+            .iter()
+            .any(|device| device.path == ld0_path && device.size.is_some()),
+        "should probe the attached device"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn wrap_an_open_loop_control_fd() {
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let lc = unsafe { LoopControl::from_raw_fd(lc.into_raw_fd()) };
+    let number = lc
+        .find_free_number()
+        .expect("should not error finding the next free loopback device number");
+
+    assert_eq!(
+        number as usize, num_devices_at_start,
+        "should find the first loopback device number"
+    );
+}
+
+#[test]
+fn open_handles_that_are_inherited_across_exec() {
+    let _lock = setup();
+
+    let is_cloexec = |fd| unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC != 0;
+
+    let lc =
+        LoopControl::open_with_flags(false).expect("should be able to open the LoopControl device");
+    assert!(!is_cloexec(lc.as_raw_fd()), "should not set O_CLOEXEC");
+    let lc =
+        LoopControl::open_with_flags(true).expect("should be able to open the LoopControl device");
+    assert!(is_cloexec(lc.as_raw_fd()), "should set O_CLOEXEC");
+
+    let path = lc
+        .next_free()
+        .expect("should not error finding the next free loopback device")
+        .path()
+        .expect("should have a path");
+    let ld =
+        LoopDevice::open_with_flags(&path, false).expect("should be able to open the loop device");
+    assert!(!is_cloexec(ld.as_raw_fd()), "should not set O_CLOEXEC");
+    let ld =
+        LoopDevice::open_with_flags(&path, true).expect("should be able to open the loop device");
+    assert!(is_cloexec(ld.as_raw_fd()), "should set O_CLOEXEC");
+}
+
+#[test]
+fn check_whether_loop_configure_is_supported() {
+    let _lock = setup();
+
+    let release = std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .expect("should be able to read the kernel release");
+    let mut version = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|part| part.parse::<u32>().expect("should be a version number"));
+    let version = (version.next().unwrap(), version.next().unwrap());
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    // The probe needs an existing device, it does not add one itself.
+    drop(
+        lc.next_free()
+            .expect("should not error finding the next free loopback device"),
+    );
+    let devices = lc.list().expect("should list the loop devices").count();
+    let supported = lc.supports_configure();
+    let cached = lc.supports_configure();
+
+    assert_eq!(
+        supported,
+        version >= (5, 8),
+        "should support LOOP_CONFIGURE from Linux 5.8"
+    );
+    assert_eq!(cached, version >= (5, 8), "should cache the result");
+    assert_eq!(
+        lc.list().expect("should list the loop devices").count(),
+        devices,
+        "should not add a device to probe"
+    );
+}
+
+#[test]
+fn find_the_next_free_device_number() {
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let number = lc
+        .find_free_number()
+        .expect("should not error finding the next free loopback device number");
+
+    assert_eq!(
+        number as usize, num_devices_at_start,
+        "should find the first loopback device number"
+    );
+}
+
+#[test]
+fn open_a_device_by_number() {
+    let _lock = setup();
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let number = lc
+        .find_free_number()
+        .expect("should not error finding the next free loopback device number");
+    let ld0 = LoopDevice::open_number(number).expect("should be able to open the loopback device");
+
+    assert_eq!(
+        ld0.path(),
+        Some(PathBuf::from(&format!("/dev/loop{}", number))),
+        "should open the loopback device with the given number"
+    );
+}
+
+#[test]
+fn wrap_an_open_device_fd() {
     let _lock = setup();
 
     let lc = LoopControl::open().expect("should be able to open the LoopControl device");
     let ld0 = lc
         .next_free()
         .expect("should not error finding the next free loopback device");
+    let path = ld0.path();
+    let ld0 = unsafe { LoopDevice::from_raw_fd(ld0.into_raw_fd()) };
+
+    assert_eq!(ld0.path(), path, "should wrap the same loopback device");
+}
+
+#[test]
+fn use_a_cloned_device_from_another_thread() {
+    let _lock = setup();
+
+    let file = create_backing_file(128 * 1024 * 1024);
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let ld0 = lc
+        .next_free()
+        .expect("should not error finding the next free loopback device");
+    let ld1 = ld0
+        .try_clone()
+        .expect("should be able to clone the loopdev");
+    assert_eq!(
+        ld1.path(),
+        ld0.path(),
+        "should clone the same loopback device"
+    );
+
+    let backing_file = file.to_path_buf();
+    std::thread::spawn(move || {
+        ld1.attach_file(&backing_file)
+            .expect("should not error attaching the backing file from the clone")
+    })
+    .join()
+    .unwrap();
+    let attached = ld0
+        .is_attached()
+        .expect("should not error checking the device");
+
+    ld0.detach().expect("should not error detaching the device");
+    file.close().expect("should delete the temp backing file");
+    assert!(attached, "the original handle should see the attach");
+}
+
+#[test]
+fn turn_a_device_into_its_file() {
+    let _lock = setup();
+
+    let file = create_backing_file(1024 * 1024);
+    std::fs::write(&file, [0xab; 4096]).expect("should be able to fill the backing file");
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let ld0 = lc
+        .next_free()
+        .expect("should not error finding the next free loopback device");
+    ld0.attach_file(&file)
+        .expect("should not error attaching the backing file to the loopdev");
+    let path = ld0.path().unwrap();
+
+    let mut device = ld0.into_file();
+    let mut data = [0; 4096];
+    device
+        .read_exact(&mut data)
+        .expect("should be able to read the device file");
+    drop(device);
+
+    loopdev::detach(&path).expect("should not error detaching the device");
+    file.close().expect("should delete the temp backing file");
+    assert_eq!(data, [0xab; 4096], "should read the backing file");
+}
+
+#[test]
+fn compare_devices_by_their_device_node() {
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let ld0 = lc
+        .next_free()
+        .expect("should not error finding the next free loopback device");
+    let path = ld0.path().expect("should have a path");
+    let same = LoopDevice::open(&path).expect("should be able to open the loop device again");
+    let other = lc
+        .open_device(num_devices_at_start as u32 + 1)
+        .expect("should be able to open the next loop device");
+
+    assert!(ld0 == same, "handles to the same device should be equal");
+    assert!(
+        ld0 != other,
+        "handles to different devices should not be equal"
+    );
+    assert!(
+        [other, same].contains(&ld0),
+        "should find the device in a list"
+    );
+}
+
+#[test]
+fn open_a_device_from_a_checked_path() {
+    let _lock = setup();
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let number = lc
+        .find_free_number()
+        .expect("should not error finding the next free loopback device number");
+    let path = PathBuf::from(format!("/dev/loop{}", number));
+    let ld0 = LoopDevice::try_from(path.as_path()).expect("should open the loopback device");
+
+    assert_eq!(
+        ld0.path(),
+        Some(path),
+        "should open the given loopback device"
+    );
+    for path in ["/dev/loop-control", "/dev/null", "/dev/loop"] {
+        assert_eq!(
+            LoopDevice::try_from(Path::new(path))
+                .expect_err("should not open a path that is not a loop device")
+                .kind(),
+            std::io::ErrorKind::InvalidInput,
+            "{} should be rejected",
+            path
+        );
+    }
+}
+
+#[test]
+fn attach_a_backing_file_default() {
+    attach_a_backing_file(0, 0, 128 * 1024 * 1024);
+}
+
+#[test]
+fn attach_a_backing_file_with_offset() {
+    attach_a_backing_file(128 * 1024, 0, 128 * 1024 * 1024);
+}
+
+#[test]
+fn attach_a_backing_file_with_sizelimit() {
+    attach_a_backing_file(0, 128 * 1024, 128 * 1024 * 1024);
+}
+
+#[test]
+fn attach_a_backing_file_with_offset_sizelimit() {
+    attach_a_backing_file(128 * 1024, 128 * 1024, 128 * 1024 * 1024);
+}
+
+#[test]
+fn read_the_data_of_a_device() {
+    let _lock = setup();
+
+    let (data, end) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(1024 * 1024);
+        let content = (0..1024 * 1024)
+            .map(|i| (i / 512) as u8)
+            .collect::<Vec<_>>();
+        std::fs::write(&file, &content).expect("should be able to fill the backing file");
+        let mut ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.with()
+            .offset(4096)
+            .size_limit(8192)
+            .attach(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+
+        let mut data = Vec::new();
+        ld0.read_to_end(&mut data)
+            .expect("should be able to read the loopdev");
+        let end = ld0
+            .stream_position()
+            .expect("should be able to get the position in the loopdev");
+        ld0.seek(SeekFrom::Start(512))
+            .expect("should be able to seek the loopdev");
+        let mut sector = [0; 512];
+        ld0.read_exact(&mut sector)
+            .expect("should be able to read the loopdev");
+        assert_eq!(
+            sector, [9; 512],
+            "should read the second sector of the slice"
+        );
+
+        ld0.detach()
+            .expect("should not error detaching the backing file");
+        file.close().expect("should delete the temp backing file");
+        (data, end)
+    };
+
+    assert_eq!(
+        data,
+        (8..24)
+            .flat_map(|sector| [sector; 512])
+            .collect::<Vec<u8>>(),
+        "should read the slice of the backing file after the offset"
+    );
+    assert_eq!(end, 8192, "should end at the sizelimit");
+
+    detach_all();
+}
+
+// This is also allowed by losetup, not sure what happens if you try to write to the file though.
+#[test]
+fn attach_a_backing_file_with_offset_overflow() {
+    attach_a_backing_file(128 * 1024 * 1024 * 2, 0, 128 * 1024 * 1024);
+}
+
+// This is also allowed by losetup, not sure what happens if you try to write to the file though.
+#[test]
+fn attach_a_backing_file_with_sizelimit_overflow() {
+    attach_a_backing_file(0, 128 * 1024 * 1024 * 2, 128 * 1024 * 1024);
+}
+
+fn attach_a_backing_file(offset: u64, sizelimit: u64, file_size: i64) {
+    let _lock = setup();
+
+    let (devices, ld0_path, file_path) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+
+        let file = create_backing_file(file_size);
+        let file_path = file.to_path_buf();
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+
+        ld0.with()
+            .offset(offset)
+            .size_limit(sizelimit)
+            .attach(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+
+        let devices = list_device(Some(ld0.path().unwrap().to_str().unwrap()));
+        file.close().expect("should delete the temp backing file");
+
+        (devices, ld0.path().unwrap(), file_path)
+    };
+
+    assert_eq!(
+        devices.len(),
+        1,
+        "there should be only one loopback mounted device"
+    );
+    assert_eq!(
+        devices[0].name.as_str(),
+        ld0_path.to_str().unwrap(),
+        "the attached devices name should match the input name"
+    );
+    assert_eq!(
+        devices[0].back_file.clone().unwrap().as_str(),
+        file_path.to_str().unwrap(),
+        "the backing file should match the given file"
+    );
+    assert_eq!(
+        devices[0].offset,
+        Some(offset),
+        "the offset should match the requested offset"
+    );
+    assert_eq!(
+        devices[0].size_limit,
+        Some(sizelimit),
+        "the sizelimit should match the requested sizelimit"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn attach_a_range_of_a_backing_file() {
+    let _lock = setup();
+
+    let (info, file_path) = {
+        let file = create_backing_file(128 * 1024 * 1024);
+        let file_path = file.to_path_buf();
+        let ld0 = LoopControl::open()
+            .expect("should be able to open the LoopControl device")
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+
+        ld0.attach_range(&file, 1024 * 1024, 4 * 1024 * 1024)
+            .expect("should not error attaching a range of the backing file");
+        let info = ld0.status().expect("should not error getting the status");
+
+        ld0.detach()
+            .expect("should not error detaching the backing file");
+        file.close().expect("should delete the temp backing file");
+        (info, file_path)
+    };
+
+    assert_eq!(info.backing_file, Some(file_path));
+    assert_eq!(
+        info.offset,
+        1024 * 1024,
+        "the offset should be the range start"
+    );
+    assert_eq!(
+        info.size_limit,
+        4 * 1024 * 1024,
+        "the sizelimit should be the range length"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn get_the_number_of_a_device() {
+    let _lock = setup();
+
+    let (number, ld0_path) = {
+        let file = create_backing_file(128 * 1024 * 1024);
+        let ld0 = LoopControl::open()
+            .expect("should be able to open the LoopControl device")
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.attach_file(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+
+        let number = ld0.number().expect("should not error getting the number");
+
+        ld0.detach()
+            .expect("should not error detaching the backing file");
+        file.close().expect("should delete the temp backing file");
+        (number, ld0.path().unwrap())
+    };
+
+    assert_eq!(
+        DeviceNumber(number).path(),
+        ld0_path,
+        "the number should match the path of the device"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn get_the_status_of_a_device() {
+    let _lock = setup();
+
+    let (info, offset, size_limit, ld0_path, file_path) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+
+        let file = create_backing_file(128 * 1024 * 1024);
+        let file_path = file.to_path_buf();
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+
+        ld0.with()
+            .offset(128 * 1024)
+            .size_limit(128 * 1024)
+            .read_only(true)
+            .attach(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+
+        let info = ld0
+            .status()
+            .expect("should not error getting the status of the loopdev");
+        let offset = ld0.offset().expect("should not error getting the offset");
+        let size_limit = ld0
+            .size_limit()
+            .expect("should not error getting the sizelimit");
+        file.close().expect("should delete the temp backing file");
+
+        (info, offset, size_limit, ld0.path().unwrap(), file_path)
+    };
+
+    assert_eq!(
+        format!("/dev/loop{}", info.number),
+        ld0_path.to_str().unwrap(),
+        "the number should match the device"
+    );
+    assert_eq!(
+        info.backing_file,
+        Some(file_path),
+        "the backing file should match the given file"
+    );
+    assert_eq!(info.offset, 128 * 1024, "the offset should match");
+    assert_eq!(info.size_limit, 128 * 1024, "the sizelimit should match");
+    assert_eq!(
+        offset, info.offset,
+        "the offset getter should match the status"
+    );
+    assert_eq!(
+        size_limit, info.size_limit,
+        "the sizelimit getter should match the status"
+    );
+    assert!(info.read_only, "the read only flag should be set");
+    assert!(!info.autoclear, "the autoclear flag should not be set");
+    assert_eq!(info.encrypt_type, 0, "there should be no encryption");
+    assert_eq!(info.crypt_name, None, "there should be no crypt name");
+
+    detach_all();
+}
+
+#[test]
+fn get_the_holders_of_a_device() {
+    let _lock = setup();
+
+    let (holders, slaves) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(128 * 1024 * 1024);
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.attach_file(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+
+        let holders = ld0.holders().expect("should not error getting the holders");
+        let slaves = ld0.slaves().expect("should not error getting the slaves");
+
+        ld0.detach()
+            .expect("should not error detaching the backing file");
+        file.close().expect("should delete the temp backing file");
+        (holders, slaves)
+    };
+
+    assert!(holders.is_empty(), "should not be held by anything");
+    assert!(slaves.is_empty(), "should not be built on another device");
+
+    detach_all();
+}
+
+#[test]
+fn probe_all_devices() {
+    let _lock = setup();
+
+    let (devices, ld0_path, file_path) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(64 * 1024 * 1024);
+        let file_path = file.to_path_buf();
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.attach_file(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+        let free = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+
+        let devices = lc.probe().expect("should not error probing the devices");
+        let free = devices
+            .iter()
+            .find(|device| Some(&device.path) == free.path().as_ref())
+            .cloned()
+            .expect("should probe the free device");
+        assert_eq!(free.size, None, "a free device should not have a size");
+        assert_eq!(free.error, None, "should probe a free device");
+
+        ld0.detach()
+            .expect("should not error detaching the backing file");
+        file.close().expect("should delete the temp backing file");
+        (devices, ld0.path().unwrap(), file_path)
+    };
+
+    let device = devices
+        .iter()
+        .find(|device| device.path == ld0_path)
+        .expect("should probe the attached device");
+    assert_eq!(device.error, None, "should probe the attached device");
+    assert_eq!(
+        device.info.backing_file,
+        Some(file_path),
+        "the backing file should match the given file"
+    );
+    assert_eq!(device.size, Some(64 * 1024 * 1024), "the size should match");
+    assert!(device.holders.is_empty(), "should not be held by anything");
+
+    detach_all();
+}
+
+#[test]
+fn get_the_backing_file_of_a_device() {
+    let _lock = setup();
+
+    let (before, attached, file_path) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(128 * 1024 * 1024);
+        let file_path = file.to_path_buf();
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+
+        let before = ld0
+            .backing_file()
+            .expect("should not error getting the backing file of a free loopdev");
+        ld0.attach_file(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+        let attached = ld0
+            .backing_file()
+            .expect("should not error getting the backing file of the loopdev");
+        file.close().expect("should delete the temp backing file");
+
+        (before, attached, file_path)
+    };
+
+    assert_eq!(before, None, "a free device should have no backing file");
+    assert_eq!(
+        attached,
+        Some(file_path),
+        "the backing file should match the given file"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn get_a_snapshot_of_a_device() {
+    let _lock = setup();
+
+    let (snapshot, info) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(128 * 1024 * 1024);
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+
+        ld0.with()
+            .offset(128 * 1024)
+            .size_limit(1024 * 1024)
+            .block_size(4096)
+            .attach(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+        let snapshot = ld0
+            .snapshot()
+            .expect("should not error getting a snapshot of the loopdev");
+        let info = ld0
+            .status()
+            .expect("should not error getting the status of the loopdev");
+        file.close().expect("should delete the temp backing file");
+
+        (snapshot, info)
+    };
+
+    assert_eq!(snapshot.info, info, "the status should match");
+    assert_eq!(snapshot.size, 1024 * 1024, "the size should match");
+    assert_eq!(snapshot.block_size, 4096, "the block size should match");
+
+    detach_all();
+}
+
+#[test]
+fn set_the_part_scan_flag_on_attach() {
+    let _lock = setup();
+
+    let info = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(128 * 1024 * 1024);
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+
+        ld0.with()
+            .part_scan(true)
+            .attach(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+        let info = ld0
+            .status()
+            .expect("should not error getting the status of the loopdev");
+        file.close().expect("should delete the temp backing file");
+
+        info
+    };
+
+    assert!(
+        info.part_scan,
+        "the kernel should report the part scan flag"
+    );
+    assert_eq!(
+        info.flags(),
+        LoopFlags::PARTSCAN,
+        "the flags should only contain the part scan flag"
+    );
+    assert!(!info.autoclear, "no other flag should be set");
+    assert!(!info.read_only, "no other flag should be set");
+
+    detach_all();
+}
+
+#[test]
+fn find_a_device_by_its_backing_file() {
+    let _lock = setup();
+
+    let (found, ld0_path) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+
+        let file = create_backing_file(128 * 1024 * 1024);
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+
+        ld0.attach_file(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+
+        let found = loopdev::find_by_backing_file(&file)
+            .expect("should not error finding the loopdev by its backing file");
+        file.close().expect("should delete the temp backing file");
+
+        (found.and_then(|ld| ld.path()), ld0.path())
+    };
+
+    assert_eq!(found, ld0_path, "should find the attached loopback device");
+
+    detach_all();
+}
+
+#[test]
+fn find_all_devices_by_their_backing_file() {
+    let _lock = setup();
+
+    let (found, attached) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+
+        let file = create_backing_file(128 * 1024 * 1024);
+        let other_file = create_backing_file(128 * 1024 * 1024);
+        let mut attached = Vec::new();
+        for (backing_file, associated) in [(&file, true), (&other_file, false), (&file, true)] {
+            let ld = lc
+                .next_free()
+                .expect("should not error finding the next free loopback device");
+            ld.attach_file(backing_file)
+                .expect("should not error attaching the backing file to the loopdev");
+            if associated {
+                attached.push(ld.path());
+            }
+        }
+
+        let found = loopdev::find_all_by_backing_file(&file)
+            .expect("should not error finding the loopdevs by their backing file");
+        file.close().expect("should delete the temp backing file");
+        other_file
+            .close()
+            .expect("should delete the temp backing file");
+
+        (
+            found.iter().map(|ld| ld.path()).collect::<Vec<_>>(),
+            attached,
+        )
+    };
+
+    assert_eq!(
+        found, attached,
+        "should find both loopback devices attached to the file"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn attach_a_read_only_backing_file() {
+    let _lock = setup();
+
+    let (devices, block_read_only) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+
+        let file = create_backing_file(128 * 1024 * 1024);
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o444))
+            .expect("should be able to make the backing file read only");
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+
+        ld0.with()
+            .read_only(true)
+            .attach(&file)
+            .expect("should not error attaching the read only backing file to the loopdev");
+
+        let devices = list_device(Some(ld0.path().unwrap().to_str().unwrap()));
+        let block_read_only = ld0
+            .is_read_only()
+            .expect("should not error getting the read only state of the loopdev");
+        file.close().expect("should delete the temp backing file");
+
+        (devices, block_read_only)
+    };
+
+    assert_eq!(
+        devices.len(),
+        1,
+        "there should be only one loopback mounted device"
+    );
+    assert!(devices[0].read_only, "the device should be read only");
+    assert!(
+        block_read_only,
+        "the device should be read only at the block layer"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn attach_with_the_status_of_another_device() {
+    let _lock = setup();
+
+    let (info0, info1, file1_path) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+
+        let file = create_backing_file(128 * 1024 * 1024);
+        let file1 = create_backing_file(128 * 1024 * 1024);
+        let file1_path = file1
+            .canonicalize()
+            .expect("should be able to resolve the backing file path");
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.with()
+            .offset(128 * 1024)
+            .size_limit(128 * 1024)
+            .read_only(true)
+            .attach(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+        let info0 = ld0
+            .status()
+            .expect("should not error getting the status of the loopdev");
+
+        let ld1 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld1.with()
+            .with_info(info0.clone())
+            .attach(&file1)
+            .expect("should not error attaching the backing file with the status");
+        let info1 = ld1
+            .status()
+            .expect("should not error getting the status of the loopdev");
+
+        file.close().expect("should delete the temp backing file");
+        file1.close().expect("should delete the temp backing file");
+
+        (info0, info1, file1_path)
+    };
+
+    assert_eq!(info1.offset, info0.offset, "the offset should match");
+    assert_eq!(
+        info1.size_limit, info0.size_limit,
+        "the sizelimit should match"
+    );
+    assert_eq!(
+        info1.read_only, info0.read_only,
+        "the read only flag should match"
+    );
+    assert_eq!(
+        info1.backing_file,
+        Some(file1_path),
+        "the backing file should be the attached file, not the one of the status"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn replace_the_backing_file_of_an_attached_device() {
+    let _lock = setup();
+
+    let (strict, replaced, info, attached, path, expected_path, file_path) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file0 = create_backing_file(128 * 1024 * 1024);
+        let file1 = create_backing_file(128 * 1024 * 1024);
+        let file_path = file1.to_path_buf();
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        let expected_path = ld0.path();
+        ld0.attach_file(&file0)
+            .expect("should not error attaching the backing file to the loopdev");
+
+        let strict = ld0.with().attach(&file1);
+        let replaced = ld0.with().replace(true).attach(&file1);
+        let info = ld0.status().ok();
+        // Whatever the outcome, the handle should still refer to the device.
+        let attached = ld0.is_attached();
+        let path = ld0.path();
+
+        drop(ld0);
+        file0.close().expect("should delete the temp backing file");
+        file1.close().expect("should delete the temp backing file");
+        (
+            strict,
+            replaced,
+            info,
+            attached,
+            path,
+            expected_path,
+            file_path,
+        )
+    };
+
+    assert_eq!(
+        strict
+            .expect_err("should error attaching a device that is in use")
+            .raw_os_error(),
+        Some(libc::EBUSY),
+        "attaching should fail with EBUSY without replace"
+    );
+    // Kernels that release the old backing file only once every handle is closed keep it busy
+    // for as long as this handle is open.
+    match replaced {
+        Ok(()) => assert_eq!(
+            info.and_then(|info| info.backing_file),
+            Some(file_path),
+            "the backing file should be replaced"
+        ),
+        Err(err) => assert_eq!(
+            err.kind(),
+            std::io::ErrorKind::ResourceBusy,
+            "replacing should fail as busy while the handle keeps the device open"
+        ),
+    }
+    assert!(attached.is_ok(), "the handle should still be usable");
+    assert_eq!(path, expected_path, "the handle should refer to the device");
+
+    detach_all();
+}
+
+#[test]
+fn attach_a_backing_file_exclusively() {
+    let _lock = setup();
+
+    let (second_attach, after_detach) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(128 * 1024 * 1024);
+
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.with()
+            .exclusive(true)
+            .attach(&file)
+            .expect("should not error attaching the backing file exclusively");
+        let ld1 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        let second_attach = ld1.with().exclusive(true).attach(&file);
+
+        ld0.detach_and_wait(Duration::from_secs(1))
+            .expect("should not error detaching the backing file from the loopdev");
+        let after_detach = ld1.with().exclusive(true).attach(&file);
+        ld1.detach()
+            .expect("should not error detaching the backing file from the loopdev");
+        file.close().expect("should delete the temp backing file");
+
+        (second_attach, after_detach)
+    };
+
+    assert_eq!(
+        second_attach
+            .expect_err("should not attach a locked backing file")
+            .kind(),
+        std::io::ErrorKind::ResourceBusy,
+        "a locked backing file should be reported as busy"
+    );
+    after_detach.expect("should attach the backing file once the lock is released");
+
+    detach_all();
+}
+
+#[cfg(feature = "direct_io")]
+#[test]
+fn attach_a_backing_file_with_direct_io() {
+    let _lock = setup();
+
+    let dir = match disk_backed_dir() {
+        Some(dir) => dir,
+        None => {
+            eprintln!("skipping, no directory that supports direct io found");
+            return;
+        }
+    };
+
+    let direct_io = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file_in(&dir, 128 * 1024 * 1024);
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+
+        ld0.with()
+            .set_direct_io(true)
+            .attach(&file)
+            .expect("should not error attaching the backing file with direct io");
+        let direct_io = ld0
+            .direct_io()
+            .expect("should not error getting the direct io flag");
+        ld0.detach()
+            .expect("should not error detaching the backing file from the loopdev");
+        file.close().expect("should delete the temp backing file");
+
+        direct_io
+    };
+
+    assert!(direct_io, "direct io should be enabled");
+
+    detach_all();
+}
+
+#[cfg(feature = "direct_io")]
+#[test]
+fn attach_a_backing_file_on_tmpfs_with_direct_io() {
+    let _lock = setup();
+
+    let dir = match tmpfs_dir() {
+        Some(dir) => dir,
+        None => {
+            eprintln!("skipping, no tmpfs directory found");
+            return;
+        }
+    };
+
+    let (result, attached, direct_io) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file_in(&dir, 1024 * 1024);
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+
+        let result = ld0.with().set_direct_io(true).attach(&file);
+        let attached = ld0
+            .is_attached()
+            .expect("should not error checking the device");
+        let direct_io = ld0.direct_io().ok();
+        if attached {
+            ld0.detach()
+                .expect("should not error detaching the backing file from the loopdev");
+        }
+        file.close().expect("should delete the temp backing file");
+
+        (result, attached, direct_io)
+    };
+
+    // tmpfs supports `O_DIRECT` since Linux 6.6, older kernels fall back to a buffered open and
+    // then can not enable direct I/O.
+    match result {
+        Ok(()) => {
+            assert!(attached, "should attach the device");
+            assert_eq!(direct_io, Some(true), "direct io should be enabled");
+        }
+        Err(err) => {
+            assert_eq!(
+                err.kind(),
+                std::io::ErrorKind::InvalidInput,
+                "should report that direct io is not supported"
+            );
+            assert!(!attached, "should detach the device again");
+        }
+    }
+
+    detach_all();
+}
+
+#[test]
+fn attach_several_devices_with_the_same_config() {
+    let _lock = setup();
+
+    let devices = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(128 * 1024 * 1024);
+        let config = AttachConfig::new()
+            .offset(128 * 1024)
+            .size_limit(1024 * 1024)
+            .read_only(true);
+
+        let mut paths = Vec::new();
+        for _ in 0..2 {
+            let ld = lc
+                .next_free()
+                .expect("should not error finding the next free loopback device");
+            ld.attach_with_config(&file, &config)
+                .expect("should not error attaching the backing file with the config");
+            paths.push(ld.path().unwrap());
+        }
+
+        let devices = list_device(None)
+            .into_iter()
+            .filter(|device| paths.iter().any(|path| path.to_str() == Some(&device.name)))
+            .collect::<Vec<_>>();
+        file.close().expect("should delete the temp backing file");
+        devices
+    };
+
+    assert_eq!(devices.len(), 2, "there should be two loopback devices");
+    for device in devices {
+        assert_eq!(device.offset, Some(128 * 1024), "the offset should match");
+        assert_eq!(
+            device.size_limit,
+            Some(1024 * 1024),
+            "the sizelimit should match"
+        );
+        assert!(device.read_only, "the read only flag should be set");
+    }
+
+    detach_all();
+}
+
+#[test]
+fn attach_an_open_file_handle() {
+    let _lock = setup();
+
+    let (devices, file_path) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+
+        let file = create_backing_file(128 * 1024 * 1024);
+        let file_path = file.to_path_buf();
+        let handle = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&file)
+            .expect("should be able to open the backing file");
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+
+        ld0.with()
+            .attach_file_handle(&handle)
+            .expect("should not error attaching the open backing file to the loopdev");
+
+        let devices = list_device(Some(ld0.path().unwrap().to_str().unwrap()));
+        file.close().expect("should delete the temp backing file");
+
+        (devices, file_path)
+    };
+
+    assert_eq!(
+        devices.len(),
+        1,
+        "there should be only one loopback mounted device"
+    );
+    assert_eq!(
+        devices[0].back_file.clone().unwrap().as_str(),
+        file_path.to_str().unwrap(),
+        "the backing file should match the given file"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn create_the_backing_file_when_attaching() {
+    let _lock = setup();
+
+    let dir = tempfile::tempdir().expect("should be able to create a temp dir");
+    let path = dir.path().join("disk.img");
+    let other = dir.path().join("other.img");
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+
+    let ld0 = lc
+        .next_free()
+        .expect("should not error finding the next free loopback device");
+    ld0.with()
+        .create_backing(1024 * 1024)
+        .attach(&path)
+        .expect("should not error creating and attaching the backing file");
+    let created_size = ld0.size().expect("should get the size of the loopdev");
+    let busy = ld0.with().create_backing(1024 * 1024).attach(&other);
+    ld0.detach_and_wait(Duration::from_secs(2))
+        .expect("should not error detaching the backing file");
+
+    let ld0 = lc
+        .next_free()
+        .expect("should not error finding the next free loopback device");
+    ld0.with()
+        .create_backing(2 * 1024 * 1024)
+        .attach(&path)
+        .expect("should not error attaching the existing backing file");
+    let existing_size = ld0.size().expect("should get the size of the loopdev");
+    ld0.detach_and_wait(Duration::from_secs(2))
+        .expect("should not error detaching the backing file");
+
+    let not_a_file = lc
+        .next_free()
+        .expect("should not error finding the next free loopback device")
+        .with()
+        .create_backing(1024 * 1024)
+        .attach(dir.path())
+        .map_err(|err| err.kind());
+
+    assert_eq!(
+        created_size,
+        1024 * 1024,
+        "should create the file with the size"
+    );
+    assert!(busy.is_err(), "should not attach to a busy device");
+    assert!(!other.exists(), "should remove the created file again");
+    assert_eq!(
+        existing_size,
+        1024 * 1024,
+        "should keep the size of an existing file"
+    );
+    assert_eq!(
+        not_a_file,
+        Err(std::io::ErrorKind::InvalidInput),
+        "should not attach a directory"
+    );
+}
+
+#[test]
+fn attach_and_keep_the_backing_file() {
+    let _lock = setup();
+
+    let (info, inode) = {
+        let file = create_backing_file(128 * 1024 * 1024);
+        let (ld0, backing_file) = LoopControl::open()
+            .expect("should be able to open the LoopControl device")
+            .next_free()
+            .expect("should not error finding the next free loopback device")
+            .with()
+            .read_only(true)
+            .attach_owned(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+
+        let info = ld0
+            .status()
+            .expect("should not error getting the status of the loopdev");
+        let inode = backing_file
+            .metadata()
+            .expect("should get the metadata of the backing file")
+            .ino();
+        file.close().expect("should delete the temp backing file");
+
+        (info, inode)
+    };
+
+    assert_eq!(
+        info.backing_inode, inode,
+        "the returned file should be the backing file"
+    );
+    assert!(info.read_only, "the read only flag should be set");
+
+    detach_all();
+}
+
+#[test]
+fn attach_a_memfd() {
+    let _lock = setup();
+
+    let devices = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+
+        let (ld0, _memfd) = ld0
+            .with()
+            .attach_memfd("loopdev-test", 128 * 1024 * 1024)
+            .expect("should not error attaching a memfd to the loopdev");
+
+        list_device(Some(ld0.path().unwrap().to_str().unwrap()))
+    };
+
+    assert_eq!(
+        devices.len(),
+        1,
+        "there should be only one loopback mounted device"
+    );
+    assert!(
+        devices[0]
+            .back_file
+            .clone()
+            .unwrap()
+            .contains("memfd:loopdev-test"),
+        "the backing file should be the memfd"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn set_autoclear_on_an_attached_device() {
+    let _lock = setup();
+
+    let (devices, offset) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+
+        let file = create_backing_file(128 * 1024 * 1024);
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+
+        ld0.with()
+            .offset(128 * 1024)
+            .attach(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+        ld0.set_autoclear(true)
+            .expect("should not error setting the autoclear flag");
+
+        let devices = list_device(Some(ld0.path().unwrap().to_str().unwrap()));
+        ld0.detach()
+            .expect("should not error detaching the backing file from the loopdev");
+        file.close().expect("should delete the temp backing file");
+
+        (devices, 128 * 1024)
+    };
+
+    assert!(devices[0].autoclear, "the autoclear flag should be set");
+    assert_eq!(
+        devices[0].offset,
+        Some(offset),
+        "the offset should be preserved when setting autoclear"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn get_the_flags_of_a_device() {
+    let _lock = setup();
+
+    let (before, after) = {
+        let file = create_backing_file(128 * 1024 * 1024);
+        let ld0 = LoopControl::open()
+            .expect("should be able to open the LoopControl device")
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.with()
+            .read_only(true)
+            .part_scan(true)
+            .attach(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+
+        let flags = |ld: &LoopDevice| {
+            (
+                ld.is_autoclear().expect("should get the autoclear flag"),
+                ld.is_part_scan().expect("should get the part scan flag"),
+                ld.is_read_only().expect("should get the read only flag"),
+            )
+        };
+        let before = flags(&ld0);
+        ld0.set_autoclear(true)
+            .expect("should not error setting the autoclear flag");
+        let after = flags(&ld0);
+
+        ld0.detach()
+            .expect("should not error detaching the backing file from the loopdev");
+        file.close().expect("should delete the temp backing file");
+        (before, after)
+    };
+
+    assert_eq!(
+        before,
+        (false, true, true),
+        "the flags should match the attach"
+    );
+    assert_eq!(
+        after,
+        (true, true, true),
+        "the autoclear flag should be set"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn pin_the_size_limit_to_the_backing_file() {
+    let _lock = setup();
+
+    let (size_limit, size) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(64 * 1024 * 1024);
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.with()
+            .offset(1024 * 1024)
+            .size_limit_from_file(true)
+            .attach(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&file)
+            .and_then(|f| f.set_len(128 * 1024 * 1024))
+            .expect("should be able to grow the backing file");
+        ld0.set_capacity()
+            .expect("should not error resizing the loopdev");
+        let size_limit = ld0
+            .size_limit()
+            .expect("should not error getting the sizelimit");
+        let size = ld0.size().expect("should get the size of the loopdev");
+
+        ld0.detach()
+            .expect("should not error detaching the backing file");
+        file.close().expect("should delete the temp backing file");
+        (size_limit, size)
+    };
+
+    assert_eq!(
+        size_limit,
+        63 * 1024 * 1024,
+        "the sizelimit should be the file length after the offset"
+    );
+    assert_eq!(
+        size,
+        63 * 1024 * 1024,
+        "the device should not grow with the backing file"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn pin_the_size_limit_to_a_backing_block_device() {
+    let _lock = setup();
+
+    let size_limit = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(64 * 1024 * 1024);
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.attach_file(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+        let ld1 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld1.with()
+            .offset(1024 * 1024)
+            .size_limit_from_file(true)
+            .attach(ld0.path().unwrap())
+            .expect("should not error attaching the loopdev to another loopdev");
+
+        let size_limit = ld1
+            .size_limit()
+            .expect("should not error getting the sizelimit");
+
+        ld1.detach()
+            .expect("should not error detaching the backing file");
+        ld0.detach()
+            .expect("should not error detaching the backing file");
+        file.close().expect("should delete the temp backing file");
+        size_limit
+    };
+
+    assert_eq!(
+        size_limit,
+        63 * 1024 * 1024,
+        "the sizelimit should be the device size after the offset"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn attach_with_a_block_size() {
+    let _lock = setup();
+
+    let (block_size, info) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(64 * 1024 * 1024);
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.with()
+            .offset(1024 * 1024)
+            .read_only(true)
+            .block_size(4096)
+            .attach(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+
+        let block_size = ld0
+            .block_size()
+            .expect("should not error getting the logical block size");
+        let info = ld0
+            .status()
+            .expect("should not error getting the status of the loopdev");
+
+        ld0.detach()
+            .expect("should not error detaching the backing file");
+        file.close().expect("should delete the temp backing file");
+        (block_size, info)
+    };
+
+    assert_eq!(block_size, 4096, "the logical block size should be set");
+    assert_eq!(info.offset, 1024 * 1024, "the offset should be set");
+    assert!(info.read_only, "the read only flag should be set");
+
+    detach_all();
+}
+
+#[test]
+fn clone_the_config_of_a_device() {
+    let _lock = setup();
+
+    let (source, target, source_block_size, target_block_size) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(64 * 1024 * 1024);
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.with()
+            .offset(1024 * 1024)
+            .size_limit(4 * 1024 * 1024)
+            .read_only(true)
+            .autoclear(true)
+            .block_size(4096)
+            .attach(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+        let ld1 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.clone_config_to(&ld1, &file)
+            .expect("should not error cloning the config of the loopdev");
+
+        let status = |ld: &LoopDevice| {
+            (
+                ld.status().expect("should not error getting the status"),
+                ld.block_size()
+                    .expect("should not error getting the logical block size"),
+            )
+        };
+        let (source, source_block_size) = status(&ld0);
+        let (target, target_block_size) = status(&ld1);
+
+        ld1.detach()
+            .expect("should not error detaching the backing file");
+        ld0.detach()
+            .expect("should not error detaching the backing file");
+        file.close().expect("should delete the temp backing file");
+        (source, target, source_block_size, target_block_size)
+    };
+
+    assert_eq!(target.offset, source.offset, "the offset should match");
+    assert_eq!(
+        target.size_limit, source.size_limit,
+        "the sizelimit should match"
+    );
+    assert_eq!(target.flags(), source.flags(), "the flags should match");
+    assert_eq!(
+        target.backing_file, source.backing_file,
+        "the backing file should match"
+    );
+    assert_eq!(
+        target_block_size, source_block_size,
+        "the logical block size should match"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn reject_invalid_options_before_attaching() {
+    let _lock = setup();
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let file = create_backing_file(1024 * 1024);
+    let ld0 = lc
+        .next_free()
+        .expect("should not error finding the next free loopback device");
+
+    let block_size = ld0.with().block_size(1000).attach(&file);
+    let direct_io = ld0
+        .with()
+        .flags(LoopFlags::DIRECT_IO | LoopFlags::READ_ONLY)
+        .offset(100)
+        .attach(&file);
+    let attached = ld0
+        .is_attached()
+        .expect("should not error checking the device");
+    file.close().expect("should delete the temp backing file");
+
+    assert_eq!(
+        block_size.map_err(|err| err.kind()),
+        Err(std::io::ErrorKind::InvalidInput),
+        "should reject a block size that is not a power of two"
+    );
+    assert_eq!(
+        direct_io.map_err(|err| err.kind()),
+        Err(std::io::ErrorKind::InvalidInput),
+        "should reject direct I/O at an unaligned offset"
+    );
+    assert!(!attached, "should not attach the device");
+}
+
+#[test]
+fn set_the_read_ahead_of_a_device() {
+    let _lock = setup();
+
+    let (read_ahead_kb, sysfs_read_ahead_kb) = {
+        let file = create_backing_file(64 * 1024 * 1024);
+        let ld0 = LoopControl::open()
+            .expect("should be able to open the LoopControl device")
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.attach_file(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+
+        ld0.set_read_ahead_kb(1024)
+            .expect("should not error setting the read-ahead");
+        let read_ahead_kb = ld0
+            .read_ahead_kb()
+            .expect("should not error getting the read-ahead");
+        let number = ld0.number().expect("should not error getting the number");
+        let sysfs_read_ahead_kb =
+            std::fs::read_to_string(format!("/sys/block/loop{}/queue/read_ahead_kb", number))
+                .expect("should be able to read the read-ahead from sysfs");
+
+        ld0.detach()
+            .expect("should not error detaching the backing file");
+        file.close().expect("should delete the temp backing file");
+        (read_ahead_kb, sysfs_read_ahead_kb)
+    };
+
+    assert_eq!(read_ahead_kb, 1024, "the read-ahead should be set");
+    assert_eq!(
+        sysfs_read_ahead_kb.trim(),
+        "1024",
+        "the read-ahead should match sysfs"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn get_the_block_sizes_of_a_device() {
+    let _lock = setup();
+
+    let (block_size, physical_block_size, geometry, discard) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(64 * 1024 * 1024);
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.with()
+            .block_size(4096)
+            .attach(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+
+        let block_size = ld0
+            .block_size()
+            .expect("should not error getting the logical block size");
+        let physical_block_size = ld0
+            .physical_block_size()
+            .expect("should not error getting the physical block size");
+        let geometry = ld0
+            .geometry()
+            .expect("should not error getting the geometry");
+        let discard = ld0
+            .discard_supported()
+            .expect("should not error checking for discard support");
+
+        ld0.detach()
+            .expect("should not error detaching the backing file");
+        file.close().expect("should delete the temp backing file");
+        (block_size, physical_block_size, geometry, discard)
+    };
+
+    assert_eq!(block_size, 4096, "the logical block size should be set");
+    assert!(
+        physical_block_size >= block_size,
+        "the physical block size should be at least the logical block size"
+    );
+    assert_eq!(
+        geometry.logical_block_size, block_size,
+        "the geometry should match the logical block size"
+    );
+    assert_eq!(
+        geometry.physical_block_size, physical_block_size,
+        "the geometry should match the physical block size"
+    );
+    assert_eq!(
+        geometry.sectors(),
+        64 * 1024 * 1024 / 4096,
+        "the geometry should count the logical blocks"
+    );
+    assert!(
+        discard,
+        "discard should be supported on a writable temp backing file"
+    );
+
+    detach_all();
+}
+
+#[test]
+fn refuse_to_change_the_offset_of_a_device() {
+    let _lock = setup();
+
+    let (detached, attached, offset) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(64 * 1024 * 1024);
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        let detached = ld0.try_set_offset(4096);
+        ld0.with()
+            .offset(1024 * 1024)
+            .attach(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+        let attached = ld0.try_set_offset(4096);
+        let offset = ld0.offset().expect("should not error getting the offset");
+
+        ld0.detach()
+            .expect("should not error detaching the backing file");
+        file.close().expect("should delete the temp backing file");
+        (detached, attached, offset)
+    };
 
-    assert_eq!(
-        ld0.path(),
-        Some(PathBuf::from(&format!("/dev/loop{}", num_devices_at_start))),
-        "should find the first loopback device"
-    );
-}
+    for (result, was_attached) in [(detached, false), (attached, true)] {
+        let err = result.expect_err("should refuse to change the offset");
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        assert_eq!(
+            err.get_ref()
+                .and_then(|err| err.downcast_ref::<loopdev::Error>()),
+            Some(&loopdev::Error::OffsetChangeUnsupported {
+                attached: was_attached
+            }),
+            "should return the typed error"
+        );
+    }
+    assert_eq!(offset, 1024 * 1024, "the offset should not change");
 
-#[test]
-fn attach_a_backing_file_default() {
-    attach_a_backing_file(0, 0, 128 * 1024 * 1024);
+    detach_all();
 }
 
 #[test]
-fn attach_a_backing_file_with_offset() {
-    attach_a_backing_file(128 * 1024, 0, 128 * 1024 * 1024);
-}
+fn resize_an_attached_device() {
+    let _lock = setup();
 
-#[test]
-fn attach_a_backing_file_with_sizelimit() {
-    attach_a_backing_file(0, 128 * 1024, 128 * 1024 * 1024);
-}
+    let (initial_size, resized, mismatch) = {
+        let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+        let file = create_backing_file(64 * 1024 * 1024);
+        let ld0 = lc
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.attach_file(&file)
+            .expect("should not error attaching the backing file to the loopdev");
 
-#[test]
-fn attach_a_backing_file_with_offset_sizelimit() {
-    attach_a_backing_file(128 * 1024, 128 * 1024, 128 * 1024 * 1024);
-}
+        let initial_size = ld0.size().expect("should get the size of the loopdev");
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&file)
+            .and_then(|f| f.set_len(128 * 1024 * 1024))
+            .expect("should be able to grow the backing file");
+        let resized = ld0
+            .set_capacity_checked(128 * 1024 * 1024)
+            .expect("should not error resizing the loopdev");
+        let mismatch = ld0.set_capacity_checked(256 * 1024 * 1024);
 
-// This is also allowed by losetup, not sure what happens if you try to write to the file though.
-#[test]
-fn attach_a_backing_file_with_offset_overflow() {
-    attach_a_backing_file(128 * 1024 * 1024 * 2, 0, 128 * 1024 * 1024);
-}
+        ld0.detach()
+            .expect("should not error detaching the backing file");
+        file.close().expect("should delete the temp backing file");
+        (initial_size, resized, mismatch)
+    };
 
-// This is also allowed by losetup, not sure what happens if you try to write to the file though.
-#[test]
-fn attach_a_backing_file_with_sizelimit_overflow() {
-    attach_a_backing_file(0, 128 * 1024 * 1024 * 2, 128 * 1024 * 1024);
+    assert_eq!(
+        initial_size,
+        64 * 1024 * 1024,
+        "the initial size should match"
+    );
+    assert_eq!(resized, 128 * 1024 * 1024, "the device should be resized");
+    assert_eq!(
+        mismatch
+            .expect_err("should error when the size does not match")
+            .kind(),
+        std::io::ErrorKind::Other,
+        "a size mismatch should be reported as Other"
+    );
+
+    detach_all();
 }
 
-fn attach_a_backing_file(offset: u64, sizelimit: u64, file_size: i64) {
+#[test]
+fn set_the_size_limit_of_an_attached_device() {
     let _lock = setup();
 
-    let (devices, ld0_path, file_path) = {
+    let (info, size) = {
         let lc = LoopControl::open().expect("should be able to open the LoopControl device");
 
-        let file = create_backing_file(file_size);
-        let file_path = file.to_path_buf();
+        let file = create_backing_file(128 * 1024 * 1024);
         let ld0 = lc
             .next_free()
             .expect("should not error finding the next free loopback device");
 
         ld0.with()
-            .offset(offset)
-            .size_limit(sizelimit)
+            .offset(128 * 1024)
             .attach(&file)
             .expect("should not error attaching the backing file to the loopdev");
+        ld0.set_size_limit(1024 * 1024)
+            .expect("should not error setting the size limit");
 
-        let devices = list_device(Some(ld0.path().unwrap().to_str().unwrap()));
+        let info = ld0
+            .status()
+            .expect("should not error getting the status of the loopdev");
+        let size = ld0.size().expect("should get the size of the loopdev");
+        ld0.detach()
+            .expect("should not error detaching the backing file from the loopdev");
         file.close().expect("should delete the temp backing file");
 
-        (devices, ld0.path().unwrap(), file_path)
+        (info, size)
     };
 
+    assert_eq!(info.size_limit, 1024 * 1024, "the sizelimit should match");
     assert_eq!(
-        devices.len(),
-        1,
-        "there should be only one loopback mounted device"
-    );
-    assert_eq!(
-        devices[0].name.as_str(),
-        ld0_path.to_str().unwrap(),
-        "the attached devices name should match the input name"
-    );
-    assert_eq!(
-        devices[0].back_file.clone().unwrap().as_str(),
-        file_path.to_str().unwrap(),
-        "the backing file should match the given file"
-    );
-    assert_eq!(
-        devices[0].offset,
-        Some(offset),
-        "the offset should match the requested offset"
+        info.offset,
+        128 * 1024,
+        "the offset should be preserved when setting the sizelimit"
     );
+    assert_eq!(size, 1024 * 1024, "the device should be resized");
+
+    detach_all();
+}
+
+#[test]
+fn update_the_options_of_an_attached_device() {
+    let _lock = setup();
+
+    let (offset_change, info, size) = {
+        let file = create_backing_file(128 * 1024 * 1024);
+        let ld0 = LoopControl::open()
+            .expect("should be able to open the LoopControl device")
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.with()
+            .offset(128 * 1024)
+            .attach(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+
+        let offset_change = ld0.update().offset(4096).size_limit(4096).apply();
+        ld0.update()
+            .offset(128 * 1024)
+            .size_limit(1024 * 1024)
+            .autoclear(true)
+            .apply()
+            .expect("should not error updating the loopdev");
+
+        let info = ld0
+            .status()
+            .expect("should not error getting the status of the loopdev");
+        let size = ld0.size().expect("should get the size of the loopdev");
+        ld0.detach()
+            .expect("should not error detaching the backing file from the loopdev");
+        file.close().expect("should delete the temp backing file");
+        (offset_change, info, size)
+    };
+
+    let err = offset_change.expect_err("should refuse to change the offset");
     assert_eq!(
-        devices[0].size_limit,
-        Some(sizelimit),
-        "the sizelimit should match the requested sizelimit"
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<loopdev::Error>()),
+        Some(&loopdev::Error::OffsetChangeUnsupported { attached: true }),
+        "should return the typed error"
     );
+    assert_eq!(info.offset, 128 * 1024, "the offset should be preserved");
+    assert_eq!(info.size_limit, 1024 * 1024, "the sizelimit should match");
+    assert!(info.autoclear, "the autoclear flag should be set");
+    assert_eq!(size, 1024 * 1024, "the device should be resized");
 
     detach_all();
 }
@@ -141,8 +2367,8 @@ fn detach_a_backing_file_with_sizelimit_overflow() {
 }
 
 fn detach_a_backing_file(offset: u64, sizelimit: u64, file_size: i64) {
-    let num_devices_at_start = list_device(None).len();
-    let _lock = setup();
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
 
     {
         let file = create_backing_file(file_size);
@@ -172,28 +2398,251 @@ fn detach_a_backing_file(offset: u64, sizelimit: u64, file_size: i64) {
     detach_all();
 }
 
+#[test]
+fn detach_all_attached_devices_with_a_report() {
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    let report = {
+        let file = create_backing_file(128 * 1024 * 1024);
+        attach_file("/dev/loop4", file.to_path_buf().to_str().unwrap(), 0, 0);
+        attach_file("/dev/loop6", file.to_path_buf().to_str().unwrap(), 0, 0);
+
+        let report = LoopControl::open()
+            .expect("should be able to open the LoopControl device")
+            .detach_all()
+            .expect("should not error detaching all loopdevs");
+
+        file.close().expect("should delete the temp backing file");
+        report
+    };
+
+    std::thread::sleep(Duration::from_millis(10));
+    for number in [4, 6] {
+        assert!(
+            report
+                .iter()
+                .any(|(n, result)| *n == DeviceNumber(number) && result.is_ok()),
+            "should detach loop{}",
+            number
+        );
+    }
+    assert_eq!(
+        list_device(None).len(),
+        num_devices_at_start,
+        "there should be no loopback devices mounted"
+    );
+    detach_all();
+}
+
+#[test]
+fn detach_and_wait_for_a_backing_file() {
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    {
+        let file = create_backing_file(128 * 1024 * 1024);
+        attach_file("/dev/loop5", file.to_path_buf().to_str().unwrap(), 0, 0);
+
+        let ld0 = LoopDevice::open("/dev/loop5")
+            .expect("should be able to open the created loopback device");
+
+        ld0.detach_and_wait(Duration::from_secs(1))
+            .expect("should not error detaching the backing file from the loopdev");
+
+        file.close().expect("should delete the temp backing file");
+    };
+
+    assert_eq!(
+        list_device(None).len(),
+        num_devices_at_start,
+        "there should be no loopback devices mounted"
+    );
+    detach_all();
+}
+
+#[test]
+fn detach_and_wait_for_the_uevent_of_a_device() {
+    let _lock = setup();
+
+    let attached = {
+        let file = create_backing_file(128 * 1024 * 1024);
+        let ld0 = LoopControl::open()
+            .expect("should be able to open the LoopControl device")
+            .next_free()
+            .expect("should not error finding the next free loopback device");
+        ld0.attach_file(&file)
+            .expect("should not error attaching the backing file to the loopdev");
+        let path = ld0.path().unwrap();
+
+        ld0.detach_and_wait_uevent(Duration::from_secs(1))
+            .expect("should not error detaching the backing file from the loopdev");
+
+        file.close().expect("should delete the temp backing file");
+        LoopDevice::open(path)
+            .expect("should be able to open the loopback device")
+            .is_attached()
+            .expect("should not error checking the device")
+    };
+
+    assert!(!attached, "the device should be detached");
+    detach_all();
+}
+
+#[test]
+fn detach_a_device_by_its_path() {
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    {
+        let file = create_backing_file(128 * 1024 * 1024);
+        attach_file("/dev/loop4", file.to_path_buf().to_str().unwrap(), 0, 0);
+
+        loopdev::detach("/dev/loop4")
+            .expect("should not error detaching the backing file from the loopdev");
+
+        file.close().expect("should delete the temp backing file");
+    };
+
+    std::thread::sleep(Duration::from_millis(10));
+    assert_eq!(
+        list_device(None).len(),
+        num_devices_at_start,
+        "there should be no loopback devices mounted"
+    );
+    detach_all();
+}
+
+#[test]
+fn ensure_a_device_is_detached_more_than_once() {
+    let _lock = setup();
+
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let file = create_backing_file(128 * 1024 * 1024);
+    let ld0 = lc
+        .next_free()
+        .expect("should not error finding the next free loopback device");
+    ld0.attach_file(&file)
+        .expect("should not error attaching the backing file to the loopdev");
+
+    ld0.ensure_detached()
+        .expect("should not error detaching the attached loopdev");
+    let attached = ld0
+        .is_attached()
+        .expect("should not error checking the device");
+    ld0.ensure_detached()
+        .expect("should not error detaching the loopdev again");
+    file.close().expect("should delete the temp backing file");
+
+    assert!(!attached, "the device should be detached");
+}
+
+#[test]
+fn detach_all_attached_devices() {
+    let lock = setup();
+    let num_devices_at_start = lock.num_devices_at_start();
+
+    let detached = {
+        let file = create_backing_file(128 * 1024 * 1024);
+        attach_file("/dev/loop4", file.to_path_buf().to_str().unwrap(), 0, 0);
+        attach_file("/dev/loop6", file.to_path_buf().to_str().unwrap(), 0, 0);
+
+        let detached = loopdev::detach_all().expect("should not error detaching all loopdevs");
+
+        file.close().expect("should delete the temp backing file");
+        detached
+    };
+
+    std::thread::sleep(Duration::from_millis(10));
+    assert_eq!(detached, 2, "should detach both loopback devices");
+    assert_eq!(
+        list_device(None).len(),
+        num_devices_at_start,
+        "there should be no loopback devices mounted"
+    );
+    detach_all();
+}
+
+#[test]
+fn detach_the_devices_backed_by_files_in_a_directory() {
+    let _lock = setup();
+
+    let (detached, remaining) = {
+        let dir = tempfile::tempdir().expect("should be able to create a temp dir");
+        let inside = create_backing_file_in(dir.path(), 128 * 1024 * 1024);
+        let deleted = create_backing_file_in(dir.path(), 128 * 1024 * 1024);
+        let outside = create_backing_file(128 * 1024 * 1024);
+        attach_file("/dev/loop4", inside.to_str().unwrap(), 0, 0);
+        attach_file("/dev/loop5", deleted.to_str().unwrap(), 0, 0);
+        attach_file("/dev/loop6", outside.to_str().unwrap(), 0, 0);
+        deleted
+            .close()
+            .expect("should delete the temp backing file");
+
+        let detached = loopdev::detach_backing_under(dir.path())
+            .expect("should not error detaching the loopdevs");
+        let remaining = list_device(None)
+            .into_iter()
+            .map(|device| device.name)
+            .collect::<Vec<_>>();
+
+        inside.close().expect("should delete the temp backing file");
+        outside
+            .close()
+            .expect("should delete the temp backing file");
+        (detached, remaining)
+    };
+
+    assert_eq!(
+        detached,
+        vec![PathBuf::from("/dev/loop4")],
+        "should only detach the device backed by a file in the directory"
+    );
+    assert!(
+        remaining.contains(&"/dev/loop5".to_string()),
+        "should skip the device whose backing file was deleted"
+    );
+    assert!(
+        remaining.contains(&"/dev/loop6".to_string()),
+        "should skip the device backed by a file outside the directory"
+    );
+    detach_all();
+}
+
 #[test]
 fn attach_a_backing_file_with_part_scan_default() {
-    attach_a_backing_file_with_part_scan(1024 * 1024);
+    attach_a_backing_file_with_part_scan(1024 * 1024, LogicalBlockSize::Lb512);
+}
+
+#[test]
+fn attach_a_backing_file_with_part_scan_4k_block_size() {
+    attach_a_backing_file_with_part_scan(4 * 1024 * 1024, LogicalBlockSize::Lb4096);
 }
 
-fn attach_a_backing_file_with_part_scan(file_size: i64) {
+fn attach_a_backing_file_with_part_scan(file_size: i64, block_size: LogicalBlockSize) {
     let _lock = setup();
 
-    let partitions = {
+    let (partitions, device_partitions) = {
         let lc = LoopControl::open().expect("should be able to open the LoopControl device");
 
         let file = create_backing_file(file_size);
-        partition_backing_file(&file, 1024);
+        partition_backing_file(&file, 1024, block_size);
 
         let ld0 = lc
             .next_free()
             .expect("should not error finding the next free loopback device");
 
         ld0.with()
+            .block_size(u64::from(block_size) as u32)
             .part_scan(true)
             .attach(&file)
             .expect("should not error attaching the backing file to the loopdev");
+        let device_partitions = ld0
+            .wait_for_partitions(1, Duration::from_secs(5))
+            .expect("should not error waiting for the partitions of the loopdev")
+            .into_iter()
+            .map(|partition| partition.display().to_string())
+            .collect::<Vec<_>>();
         let devices = list_device(Some(ld0.path().unwrap().to_str().unwrap()));
         let partitions = glob::glob(&format!("{}p*", devices[0].name))
             .unwrap()
@@ -202,7 +2651,7 @@ fn attach_a_backing_file_with_part_scan(file_size: i64) {
 
         file.close().expect("should delete the temp backing file");
 
-        partitions
+        (partitions, device_partitions)
     };
 
     assert_eq!(
@@ -210,13 +2659,131 @@ fn attach_a_backing_file_with_part_scan(file_size: i64) {
         1,
         "there should be only one partition for the device"
     );
+    assert_eq!(
+        device_partitions, partitions,
+        "the partitions of the device should match the partition nodes"
+    );
 }
 
 #[test]
-fn add_a_loop_device() {
+fn attach_a_backing_file_with_several_partitions_with_part_scan() {
+    attach_a_backing_file_with_several_partitions(LogicalBlockSize::Lb512);
+}
+
+#[test]
+fn attach_a_backing_file_with_several_partitions_with_part_scan_4k_block_size() {
+    attach_a_backing_file_with_several_partitions(LogicalBlockSize::Lb4096);
+}
+
+fn attach_a_backing_file_with_several_partitions(block_size: LogicalBlockSize) {
     let _lock = setup();
 
+    let file = create_backing_file(4 * 1024 * 1024);
+    let offsets =
+        partition_backing_file_n(&file, &[512 * 1024, 1024 * 1024, 512 * 1024], block_size);
+
     let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let ld0 = lc
+        .next_free()
+        .expect("should not error finding the next free loopback device");
+    ld0.with()
+        .block_size(u64::from(block_size) as u32)
+        .part_scan(true)
+        .attach(&file)
+        .expect("should not error attaching the backing file to the loopdev");
+    let path = ld0.path().unwrap();
+    let partitions = ld0
+        .wait_for_partitions(offsets.len(), Duration::from_secs(5))
+        .expect("should not error waiting for the partitions of the loopdev");
+
+    assert_eq!(
+        partitions,
+        (1..=offsets.len())
+            .map(|n| PathBuf::from(format!("{}p{}", path.display(), n)))
+            .collect::<Vec<_>>(),
+        "should list every partition in order"
+    );
+    for (partition, offset) in partitions.iter().zip(&offsets) {
+        let name = partition.file_name().unwrap().to_str().unwrap();
+        let start = std::fs::read_to_string(format!(
+            "/sys/block/{}/{}/start",
+            path.file_name().unwrap().to_str().unwrap(),
+            name
+        ))
+        .expect("should be able to read the start of the partition");
+        // sysfs counts in 512 byte sectors whatever the logical block size is.
+        assert_eq!(
+            start.trim().parse::<u64>().unwrap() * 512,
+            *offset,
+            "the partition should start at the expected offset"
+        );
+    }
+
+    ld0.detach().expect("should not error detaching the device");
+    file.close().expect("should delete the temp backing file");
+}
+
+#[test]
+fn add_a_loop_device() {
+    let _lock = setup();
+
+    let lc = LoopControl::open()
+        .expect("should be able to open the LoopControl device")
+        .scoped();
     assert!(lc.add(1).is_ok());
     assert!(lc.add(1).is_err());
 }
+
+#[test]
+fn remove_a_loop_device() {
+    let _lock = setup();
+
+    let number = (256..)
+        .find(|&number| !DeviceNumber(number).path().exists())
+        .expect("should find an unused loopback device number");
+    let lc = LoopControl::open().expect("should be able to open the LoopControl device");
+    let ld = lc
+        .add(number)
+        .expect("should not error adding the loopback device");
+
+    let busy = lc.remove(number);
+    drop(ld);
+    let removed = lc.remove(number);
+    let missing = lc.remove(number);
+
+    assert_eq!(
+        busy.map_err(|err| err.kind()),
+        Err(std::io::ErrorKind::ResourceBusy),
+        "should not remove a device that is still open"
+    );
+    removed.expect("should not error removing the loopback device");
+    assert_eq!(
+        missing.map_err(|err| err.kind()),
+        Err(std::io::ErrorKind::NotFound),
+        "should not remove a device that does not exist"
+    );
+}
+
+#[test]
+fn remove_the_added_devices_of_a_scoped_loop_control() {
+    let _lock = setup();
+
+    let number = (256..)
+        .find(|&number| !DeviceNumber(number).path().exists())
+        .expect("should find an unused loopback device number");
+    let path = {
+        let lc = LoopControl::open()
+            .expect("should be able to open the LoopControl device")
+            .scoped();
+        let ld = lc
+            .add(number)
+            .expect("should not error adding the loopback device");
+        ld.path().unwrap()
+    };
+
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(
+        !path.exists() && !Path::new(&format!("/sys/block/loop{}", number)).exists(),
+        "the added device should be removed"
+    );
+}
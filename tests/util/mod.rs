@@ -1,24 +1,52 @@
-use libc::fallocate;
+use libc::{fallocate, flock, LOCK_EX};
+use loopdev::{LoopControl, LoopDevice};
 use serde::{Deserialize, Deserializer};
+#[cfg(feature = "direct_io")]
+use std::{ffi::CString, os::unix::ffi::OsStrExt};
 use std::{
-    fs::OpenOptions,
+    fs::{File, OpenOptions},
     io,
     os::unix::io::AsRawFd,
-    path::Path,
+    path::{Path, PathBuf},
     process::Command,
     sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
 };
 
 use tempfile::{NamedTempFile, TempPath};
 
 // All tests use the same loopback device interface and so can tread on each others toes leading to
-// racy tests. So we need to lock all tests to ensure only one runs at a time.
+// racy tests. So we need to lock all tests to ensure only one runs at a time. The mutex serializes
+// the tests of this process, the file lock serializes them with other test processes.
 lazy_static::lazy_static! {
     static ref LOCK: Arc<Mutex<()>> = Arc::new(Mutex::new(()));
+    // Devices that were attached before the first test of this process took the lock belong to
+    // the host, ie snapd, and are never detached by the tests.
+    static ref HOST_DEVICES: Vec<PathBuf> = attached_devices();
+}
+const LOCK_PATH: &str = "/tmp/loopdev-tests.lock";
+
+/// Held for the duration of a test, see [`setup`].
+pub struct TestLock {
+    _guard: MutexGuard<'static, ()>,
+    _file: File,
+    devices_at_start: Vec<String>,
+}
+
+impl TestLock {
+    /// The number of loop devices that were still attached once the lock was taken and the
+    /// devices were cleaned up, ie devices in use by the host.
+    pub fn num_devices_at_start(&self) -> usize {
+        self.devices_at_start.len()
+    }
 }
 
 pub fn create_backing_file(size: i64) -> TempPath {
-    let file = NamedTempFile::new().expect("should be able to create a temp file");
+    create_backing_file_in(std::env::temp_dir(), size)
+}
+
+pub fn create_backing_file_in(dir: impl AsRef<Path>, size: i64) -> TempPath {
+    let file = NamedTempFile::new_in(dir).expect("should be able to create a temp file");
     assert!(
         unsafe { fallocate(file.as_raw_fd(), 0, 0, size) } >= 0,
         "should be able to allocate the temp file: {}",
@@ -27,38 +55,108 @@ pub fn create_backing_file(size: i64) -> TempPath {
     file.into_temp_path()
 }
 
-pub fn partition_backing_file(backing_file: impl AsRef<Path>, size: u64) {
-    gpt::mbr::ProtectiveMBR::new()
+/// Finds a directory for backing files that is not on tmpfs, which does not support direct I/O.
+/// `LOOPDEV_TEST_DIR` is tried first, then the temp dir (`TMPDIR`) and `/var/tmp`.
+#[cfg(feature = "direct_io")]
+pub fn disk_backed_dir() -> Option<PathBuf> {
+    std::env::var_os("LOOPDEV_TEST_DIR")
+        .map(PathBuf::from)
+        .into_iter()
+        .chain([std::env::temp_dir(), PathBuf::from("/var/tmp")])
+        .find(|dir| is_tmpfs(dir) == Some(false))
+}
+
+/// Finds a directory for backing files on tmpfs, trying `/dev/shm` and then the temp dir.
+#[cfg(feature = "direct_io")]
+pub fn tmpfs_dir() -> Option<PathBuf> {
+    [PathBuf::from("/dev/shm"), std::env::temp_dir()]
+        .into_iter()
+        .find(|dir| is_tmpfs(dir) == Some(true))
+}
+
+#[cfg(feature = "direct_io")]
+fn is_tmpfs(dir: &Path) -> Option<bool> {
+    let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::statfs(path.as_ptr(), &mut stat) };
+    (ret == 0).then_some(stat.f_type == libc::TMPFS_MAGIC)
+}
+
+pub fn partition_backing_file(
+    backing_file: impl AsRef<Path>,
+    size: u64,
+    block_size: gpt::disk::LogicalBlockSize,
+) {
+    partition_backing_file_n(backing_file, &[size], block_size);
+}
+
+/// Lays down a GPT with a partition for each of `sizes` and returns the offsets of the
+/// partitions in bytes, in partition order.
+pub fn partition_backing_file_n(
+    backing_file: impl AsRef<Path>,
+    sizes: &[u64],
+    block_size: gpt::disk::LogicalBlockSize,
+) -> Vec<u64> {
+    gpt::mbr::ProtectiveMBR::with_lb_size(u64::from(block_size) as u32)
         .overwrite_lba0(&mut OpenOptions::new().write(true).open(&backing_file).unwrap())
         .expect("failed to write MBR");
 
     let mut disk = gpt::GptConfig::new()
         .initialized(false)
         .writable(true)
-        .logical_block_size(gpt::disk::LogicalBlockSize::Lb512)
+        .logical_block_size(block_size)
         .open(backing_file)
         .expect("could not open backing file");
 
     disk.update_partitions(std::collections::BTreeMap::<u32, gpt::partition::Partition>::new())
         .expect("coult not initialize blank partition table");
 
-    disk.add_partition(
-        "Linux filesystem",
-        size,
-        gpt::partition_types::LINUX_FS,
-        0,
-        None,
-    )
-    .expect("could not create partition");
+    let offsets = sizes
+        .iter()
+        .map(|&size| {
+            let id = disk
+                .add_partition(
+                    "Linux filesystem",
+                    size,
+                    gpt::partition_types::LINUX_FS,
+                    0,
+                    None,
+                )
+                .expect("could not create partition");
+            disk.partitions()[&id].first_lba * u64::from(block_size)
+        })
+        .collect();
 
     disk.write()
         .expect("could not write partition table to backing file");
+
+    offsets
 }
 
-pub fn setup() -> MutexGuard<'static, ()> {
-    let lock = LOCK.lock().unwrap();
+pub fn setup() -> TestLock {
+    let guard = LOCK.lock().unwrap();
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(LOCK_PATH)
+        .expect("should be able to open the test lock file");
+    assert!(
+        unsafe { flock(file.as_raw_fd(), LOCK_EX) } == 0,
+        "should be able to lock the test lock file: {}",
+        io::Error::last_os_error()
+    );
+    lazy_static::initialize(&HOST_DEVICES);
     detach_all();
-    lock
+    let devices_at_start = list_device(None)
+        .into_iter()
+        .map(|device| device.name)
+        .collect();
+    TestLock {
+        _guard: guard,
+        _file: file,
+        devices_at_start,
+    }
 }
 
 pub fn attach_file(loop_dev: &str, backing_file: &str, offset: u64, sizelimit: u64) {
@@ -79,17 +177,29 @@ pub fn attach_file(loop_dev: &str, backing_file: &str, offset: u64, sizelimit: u
     }
 }
 
+/// Detaches every device attached by the tests, leaving the devices of the host alone.
 pub fn detach_all() {
-    std::thread::sleep(std::time::Duration::from_millis(10));
-    if !Command::new("losetup")
-        .args(["-D"])
-        .status()
-        .expect("failed to cleanup existing loop devices")
-        .success()
-    {
-        panic!("failed to cleanup existing loop devices")
+    for path in attached_devices() {
+        if HOST_DEVICES.contains(&path) {
+            continue;
+        }
+        let ld = LoopDevice::open(&path).expect("should be able to open the loop device");
+        if ld.is_attached().expect("should be able to get the status") {
+            ld.detach_and_wait(Duration::from_secs(1))
+                .expect("failed to cleanup existing loop devices");
+        }
     }
-    std::thread::sleep(std::time::Duration::from_millis(10));
+}
+
+fn attached_devices() -> Vec<PathBuf> {
+    LoopControl::open()
+        .expect("should be able to open the LoopControl device")
+        .probe()
+        .expect("should be able to probe the loop devices")
+        .into_iter()
+        .filter(|device| device.size.is_some())
+        .map(|device| device.path)
+        .collect()
 }
 
 pub fn list_device(dev_file: Option<&str>) -> Vec<LoopDeviceOutput> {
@@ -122,6 +232,11 @@ pub struct LoopDeviceOutput {
     #[serde(rename = "back-file")]
     //#[serde(deserialize_with = "deserialize_nullable_string")]
     pub back_file: Option<String>,
+    #[serde(deserialize_with = "deserialize_bool_from_string")]
+    pub autoclear: bool,
+    #[serde(rename = "ro")]
+    #[serde(deserialize_with = "deserialize_bool_from_string")]
+    pub read_only: bool,
 }
 
 #[derive(Deserialize, Debug)]
@@ -148,3 +263,20 @@ where
         StringOrInt::Number(Some(i)) => Ok(Some(i)),
     }
 }
+
+pub fn deserialize_bool_from_string<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrBool {
+        String(String),
+        Bool(bool),
+    }
+
+    match StringOrBool::deserialize(deserializer)? {
+        StringOrBool::String(s) => Ok(s == "1"),
+        StringOrBool::Bool(b) => Ok(b),
+    }
+}